@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::time::Instant;
+
+use crc::crc32;
+
+use nrf_dfu::buffered::BufferedDevice;
+use nrf_dfu::codec::DfuCodec;
+use nrf_dfu::slip::SlipCodec;
+use nrf_dfu::updater::{NordicDevice, ResetMode, Updater};
+
+const IMAGE_SIZE: usize = 150 * 1024;
+const ITERATIONS: u32 = 20;
+
+#[derive(Default)]
+struct Object {
+    data: Vec<u8>,
+    crc: u32,
+    executed: usize,
+    executed_crc: u32,
+}
+
+// Just enough of the bootloader state machine to answer every request the updater makes.
+#[derive(Default)]
+struct SimulatedBootloader {
+    frame: Vec<u8>,
+    escaped: bool,
+    output: VecDeque<u8>,
+    prn: u16,
+    writes: u16,
+    objects: [Object; 2],
+    current: usize,
+    reads: usize,
+}
+
+impl SimulatedBootloader {
+    fn respond(&mut self, opcode: u8, payload: &[u8]) {
+        let mut frame = vec![];
+        SlipCodec::encoded_write_chained(&mut frame, &[&[0x60, opcode, 0x01], payload]).unwrap();
+        self.output.extend(frame);
+    }
+
+    fn crc_payload(&self) -> Vec<u8> {
+        let object = &self.objects[self.current];
+        let offset = object.data.len() as u32;
+        [offset.to_le_bytes(), object.crc.to_le_bytes()].concat()
+    }
+
+    fn handle(&mut self, request: &[u8]) {
+        let (opcode, payload) = (request[0], &request[1..]);
+        match opcode {
+            0x00 => self.respond(0x00, &[0x01]),
+            0x01 => {
+                self.current = payload[0] as usize - 1;
+                let object = &mut self.objects[self.current];
+                object.data.truncate(object.executed);
+                object.crc = object.executed_crc;
+                self.writes = 0;
+                self.respond(0x01, &[]);
+            }
+            0x02 => {
+                self.prn = u16::from_le_bytes([payload[0], payload[1]]);
+                self.respond(0x02, &[]);
+            }
+            0x03 => {
+                let crc = self.crc_payload();
+                self.respond(0x03, &crc);
+            }
+            0x04 => {
+                let object = &mut self.objects[self.current];
+                object.executed = object.data.len();
+                object.executed_crc = object.crc;
+                self.respond(0x04, &[]);
+            }
+            0x06 => {
+                self.current = payload[0] as usize - 1;
+                let payload = [4096u32.to_le_bytes().to_vec(), self.crc_payload()].concat();
+                self.respond(0x06, &payload);
+            }
+            0x07 => self.respond(0x07, &515u16.to_le_bytes()),
+            0x08 => {
+                let object = &mut self.objects[self.current];
+                object.data.extend_from_slice(payload);
+                object.crc = crc32::update(object.crc, &crc32::IEEE_TABLE, payload);
+                self.writes += 1;
+                if self.prn > 0 && self.writes == self.prn {
+                    self.writes = 0;
+                    let crc = self.crc_payload();
+                    self.respond(0x03, &crc);
+                }
+            }
+            0x09 => self.respond(0x09, payload),
+            _ => {}
+        }
+    }
+}
+
+impl Read for SimulatedBootloader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        let count = buf.len().min(self.output.len());
+        for byte in buf.iter_mut().take(count) {
+            *byte = self.output.pop_front().unwrap();
+        }
+        Ok(count)
+    }
+}
+
+impl Write for SimulatedBootloader {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            match (self.escaped, byte) {
+                (true, 0xDC) => self.frame.push(0xC0),
+                (true, 0xDD) => self.frame.push(0xDB),
+                (false, 0xDB) => {
+                    self.escaped = true;
+                    continue;
+                }
+                (false, 0xC0) => {
+                    let request = std::mem::take(&mut self.frame);
+                    self.handle(&request);
+                }
+                (_, byte) => self.frame.push(byte),
+            }
+            self.escaped = false;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl NordicDevice for SimulatedBootloader {
+    type Codec = SlipCodec;
+    fn reset(&mut self, _mode: ResetMode) {}
+}
+
+fn flash<D: NordicDevice>(device: &mut D, image: &[u8]) {
+    // try_init drives the same transfer_object loop as update, minus the settle delays.
+    Updater::new(device, false).try_init(image).unwrap();
+}
+
+fn bench(label: &str, image: &[u8], run: impl Fn(&[u8]) -> SimulatedBootloader) {
+    let start = Instant::now();
+    let mut reads = 0;
+    for _ in 0..ITERATIONS {
+        let device = run(image);
+        assert_eq!(device.objects[0].executed, IMAGE_SIZE);
+        reads = device.reads;
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+
+    println!(
+        "{}: transfer of {} KB image: {:?} per iteration ({:.1} MB/s, {} reads)",
+        label,
+        IMAGE_SIZE / 1024,
+        elapsed,
+        IMAGE_SIZE as f64 / elapsed.as_secs_f64() / 1e6,
+        reads
+    );
+}
+
+fn main() {
+    let image: Vec<u8> = (0..IMAGE_SIZE).map(|i| (i * 31) as u8).collect();
+
+    bench("unbuffered", &image, |image| {
+        let mut device = SimulatedBootloader::default();
+        flash(&mut device, image);
+        device
+    });
+    bench("buffered", &image, |image| {
+        let mut device = BufferedDevice::new(SimulatedBootloader::default());
+        flash(&mut device, image);
+        device.into_inner()
+    });
+}
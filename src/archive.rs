@@ -1,78 +1,1002 @@
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::Path;
+use std::sync::OnceLock;
 
+use crc::crc32;
+use flate2::read::GzDecoder;
 use zip::read::ZipArchive;
+use zip::result::ZipError;
+use zip::write::{FileOptions, ZipWriter};
 
 use serde::{Deserialize, Serialize};
 
+use crate::init_packet::{InitPacket, InitPacketSet};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Firmware {
+    pub bin_file: String,
+    pub dat_file: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ManifestInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootloader: Option<Firmware>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub softdevice_bootloader: Option<Firmware>,
+    #[serde(
+        rename = "application",
+        default,
+        with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub applications: Vec<Firmware>,
+}
+
+// Multi-image archives (e.g. nRF5340 app and net core) list applications as an array;
+// everything else uses a single object.
+mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::Firmware;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Firmware),
+        Many(Vec<Firmware>),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Firmware>, D::Error> {
+        Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+            None => vec![],
+            Some(OneOrMany::One(firmware)) => vec![firmware],
+            Some(OneOrMany::Many(firmware)) => firmware,
+        })
+    }
+
+    pub fn serialize<S: Serializer>(firmware: &[Firmware], serializer: S) -> Result<S::Ok, S::Error> {
+        match firmware {
+            [firmware] => firmware.serialize(serializer),
+            firmware => firmware.serialize(serializer),
+        }
+    }
+}
+
+impl ManifestInfo {
+    fn firmware(&self) -> impl Iterator<Item = &Firmware> {
+        self.bootloader
+            .iter()
+            .chain(self.softdevice_bootloader.iter())
+            .chain(self.applications.iter())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
-struct Firmware {
+struct Manifest {
+    manifest: ManifestInfo,
+}
+
+// Legacy nrfutil (0.5.x) manifests carry a dfu_version and per-image init_packet_data.
+// Unknown image keys are rejected so a slot we can't model, like a standalone
+// softdevice, fails loudly instead of being skipped.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LegacyManifestField {
+    #[allow(dead_code)]
+    dfu_version: f32,
+    bootloader: Option<LegacyFirmware>,
+    softdevice_bootloader: Option<LegacyFirmware>,
+    application: Option<LegacyFirmware>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LegacyFirmware {
     bin_file: String,
     dat_file: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ManifestField {
-    bootloader: Option<Firmware>,
-    softdevice_bootloader: Option<Firmware>,
-    application: Option<Firmware>,
+#[derive(Deserialize, Debug)]
+struct LegacyManifest {
+    manifest: LegacyManifestField,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Manifest {
-    manifest: ManifestField,
+impl From<LegacyFirmware> for Firmware {
+    fn from(LegacyFirmware { bin_file, dat_file }: LegacyFirmware) -> Firmware {
+        Firmware { bin_file, dat_file }
+    }
+}
+
+fn parse_manifest(manifest_data: &str) -> Result<ManifestInfo, ArchiveError> {
+    let value: serde_json::Value = serde_json::from_str(manifest_data)?;
+
+    if value["manifest"].get("dfu_version").is_some() {
+        let LegacyManifest { manifest } = serde_json::from_value(value)?;
+        Ok(ManifestInfo {
+            bootloader: manifest.bootloader.map(Firmware::from),
+            softdevice_bootloader: manifest.softdevice_bootloader.map(Firmware::from),
+            applications: manifest.application.map(Firmware::from).into_iter().collect(),
+        })
+    } else {
+        let Manifest { manifest } = serde_json::from_value(value)?;
+        Ok(manifest)
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    IOError(std::io::Error),
+    ZipError(ZipError),
+    JsonError(serde_json::Error),
+    MissingManifest(Vec<String>),
+    MissingEntry(String),
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(err: std::io::Error) -> ArchiveError {
+        ArchiveError::IOError(err)
+    }
 }
 
+impl From<ZipError> for ArchiveError {
+    fn from(err: ZipError) -> ArchiveError {
+        ArchiveError::ZipError(err)
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(err: serde_json::Error) -> ArchiveError {
+        ArchiveError::JsonError(err)
+    }
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ImageKind {
+    SoftdeviceBootloader,
+    Bootloader,
+    Application,
+}
+
+// A set of image kinds, e.g. `ModuleSelection::APPLICATION` to leave the bootloader
+// in an archive alone.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ModuleSelection(u8);
+
+impl ModuleSelection {
+    pub const SOFTDEVICE_BOOTLOADER: ModuleSelection = ModuleSelection(0x01);
+    pub const BOOTLOADER: ModuleSelection = ModuleSelection(0x02);
+    pub const APPLICATION: ModuleSelection = ModuleSelection(0x04);
+    pub const ALL: ModuleSelection = ModuleSelection(0x07);
+
+    pub fn contains(self, kind: ImageKind) -> bool {
+        let bit = match kind {
+            ImageKind::SoftdeviceBootloader => ModuleSelection::SOFTDEVICE_BOOTLOADER,
+            ImageKind::Bootloader => ModuleSelection::BOOTLOADER,
+            ImageKind::Application => ModuleSelection::APPLICATION,
+        };
+        self.0 & bit.0 != 0
+    }
+}
+
+impl std::ops::BitOr for ModuleSelection {
+    type Output = ModuleSelection;
+
+    fn bitor(self, other: ModuleSelection) -> ModuleSelection {
+        ModuleSelection(self.0 | other.0)
+    }
+}
+
+// The binary is read-only once loaded, so the CRC computed from it stays valid.
 pub struct FirmwareData {
-    pub bin: Vec<u8>,
+    bin: Vec<u8>,
     pub dat: Vec<u8>,
+    pub init: Option<InitPacket>,
+    crc: OnceLock<u32>,
+}
+
+impl FirmwareData {
+    // `init` is left empty when the .dat isn't a protobuf init packet, as with legacy DFU.
+    pub fn new(bin: Vec<u8>, dat: Vec<u8>) -> FirmwareData {
+        let init = InitPacket::parse(&dat).ok();
+        FirmwareData { bin, dat, init, crc: OnceLock::new() }
+    }
+
+    pub fn bin(&self) -> &[u8] {
+        &self.bin
+    }
+
+    pub fn len(&self) -> usize {
+        self.bin.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bin.is_empty()
+    }
+
+    // IEEE CRC-32 of the firmware binary, computed on first use.
+    pub fn crc32(&self) -> u32 {
+        *self.crc.get_or_init(|| crc32::checksum_ieee(&self.bin))
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImageSummary {
+    pub kind: ImageKind,
+    pub size: usize,
+    pub crc32: u32,
 }
 
 pub struct FirmwareArchive {
     pub bootloader: Option<FirmwareData>,
     pub softdevice_bootloader: Option<FirmwareData>,
-    pub application: Option<FirmwareData>,
+    pub applications: Vec<FirmwareData>,
+    pub manifest: ManifestInfo,
+    pub raw_manifest: String,
 }
 
 impl FirmwareArchive {
-    pub fn new(path: &str) -> FirmwareArchive {
-        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
-        let mut manifest_data = String::new();
-
-        {
-            let mut manifest = archive.by_name("manifest.json").unwrap();
-            manifest.read_to_string(&mut manifest_data).unwrap();
-        }
-
-        let Manifest {
-            manifest:
-                ManifestField {
-                    bootloader,
-                    softdevice_bootloader,
-                    application,
-                },
-        } = serde_json::from_str(&manifest_data).unwrap();
-
-        let mut extract_data = |Firmware { bin_file, dat_file }| {
-            let mut bin = vec![];
-            {
-                let mut bin_file = archive.by_name(&bin_file).unwrap();
-                bin_file.read_to_end(&mut bin).unwrap();
+    // A combined SoftDevice+bootloader image supersedes a standalone bootloader.
+    pub fn images(&self) -> Vec<(ImageKind, &FirmwareData)> {
+        self.selected_images(ModuleSelection::ALL)
+    }
+
+    // The init packets parsed when the archive was loaded, in `images` order.
+    pub fn init_packets(&self) -> InitPacketSet {
+        InitPacketSet {
+            packets: self.images().into_iter().map(|(_, image)| image.init.clone()).collect(),
+        }
+    }
+
+    // As `images`, limited to `modules`. A standalone bootloader is only superseded
+    // when the SoftDevice+bootloader image is itself selected.
+    pub fn selected_images(&self, modules: ModuleSelection) -> Vec<(ImageKind, &FirmwareData)> {
+        let mut images = vec![];
+        let softdevice_bootloader = self
+            .softdevice_bootloader
+            .as_ref()
+            .filter(|_| modules.contains(ImageKind::SoftdeviceBootloader));
+        let bootloader = self.bootloader.as_ref().filter(|_| modules.contains(ImageKind::Bootloader));
+
+        if let Some(softdevice_bootloader) = softdevice_bootloader {
+            images.push((ImageKind::SoftdeviceBootloader, softdevice_bootloader));
+        } else if let Some(bootloader) = bootloader {
+            images.push((ImageKind::Bootloader, bootloader));
+        }
+
+        if modules.contains(ImageKind::Application) {
+            for application in &self.applications {
+                images.push((ImageKind::Application, application));
+            }
+        }
+
+        images
+    }
+
+    // Size and CRC of each image `images` would flash, e.g. for comparing with the device.
+    pub fn summary(&self) -> Vec<ImageSummary> {
+        self.images()
+            .into_iter()
+            .map(|(kind, image)| ImageSummary {
+                kind,
+                size: image.len(),
+                crc32: image.crc32(),
+            })
+            .collect()
+    }
+
+    pub fn new(path: &str) -> Result<FirmwareArchive, ArchiveError> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    pub fn from_reader<R: Read + Seek>(reader: R) -> Result<FirmwareArchive, ArchiveError> {
+        Self::from_reader_with_gunzip(reader, true)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<FirmwareArchive, ArchiveError> {
+        Self::from_reader(Cursor::new(bytes))
+    }
+
+    // Some build systems gzip the bin/dat before storing them in the zip; with `gunzip`
+    // set, such entries are decompressed on extraction. The constructors without
+    // `_with_gunzip` always set it.
+    pub fn new_with_gunzip(path: &str, gunzip: bool) -> Result<FirmwareArchive, ArchiveError> {
+        Self::from_reader_with_gunzip(File::open(path)?, gunzip)
+    }
+
+    pub fn from_reader_with_gunzip<R: Read + Seek>(reader: R, gunzip: bool) -> Result<FirmwareArchive, ArchiveError> {
+        Self::from_zip(ZipArchive::new(reader)?, gunzip)
+    }
+
+    pub fn from_bytes_with_gunzip(bytes: &[u8], gunzip: bool) -> Result<FirmwareArchive, ArchiveError> {
+        Self::from_reader_with_gunzip(Cursor::new(bytes), gunzip)
+    }
+
+    pub fn from_dir_with_gunzip(path: &Path, gunzip: bool) -> Result<FirmwareArchive, ArchiveError> {
+        Self::load(&mut DirSource(path), "manifest.json", gunzip)
+    }
+
+    pub fn manifest_json(&self) -> &str {
+        &self.raw_manifest
+    }
+
+    pub fn from_dir(path: &Path) -> Result<FirmwareArchive, ArchiveError> {
+        Self::from_dir_with_gunzip(path, true)
+    }
+
+    fn from_zip<R: Read + Seek>(mut archive: ZipArchive<R>, gunzip: bool) -> Result<FirmwareArchive, ArchiveError> {
+        let manifest_path = find_manifest(&archive)?;
+        Self::load(&mut archive, &manifest_path, gunzip)
+    }
+
+    fn load<S: ArchiveSource>(source: &mut S, manifest_path: &str, gunzip: bool) -> Result<FirmwareArchive, ArchiveError> {
+        let manifest_dir = &manifest_path[..manifest_path.len() - "manifest.json".len()];
+        let manifest_data = String::from_utf8(source.read(manifest_path)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        let manifest = parse_manifest(&manifest_data)?;
+
+        for firmware in manifest.firmware() {
+            for name in &[&firmware.bin_file, &firmware.dat_file] {
+                let path = format!("{}{}", manifest_dir, name);
+                if !source.contains(&path) {
+                    return Err(ArchiveError::MissingEntry(path));
+                }
             }
+        }
 
-            let mut dat = vec![];
-            {
-                let mut dat_file = archive.by_name(&dat_file).unwrap();
-                dat_file.read_to_end(&mut dat).unwrap();
+        let mut extract_data = |Firmware { bin_file, dat_file }: &Firmware| -> Result<FirmwareData, ArchiveError> {
+            let mut bin = source.read(&format!("{}{}", manifest_dir, bin_file))?;
+            let mut dat = source.read(&format!("{}{}", manifest_dir, dat_file))?;
+
+            if gunzip {
+                bin = gunzip_if_compressed(bin)?;
+                dat = gunzip_if_compressed(dat)?;
             }
 
-            FirmwareData { bin, dat }
+            Ok(FirmwareData::new(bin, dat))
         };
 
-        FirmwareArchive {
-            bootloader: bootloader.map(&mut extract_data),
-            softdevice_bootloader: softdevice_bootloader.map(&mut extract_data),
-            application: application.map(&mut extract_data),
+        Ok(FirmwareArchive {
+            bootloader: manifest.bootloader.as_ref().map(&mut extract_data).transpose()?,
+            softdevice_bootloader: manifest.softdevice_bootloader.as_ref().map(&mut extract_data).transpose()?,
+            applications: manifest
+                .applications
+                .iter()
+                .map(&mut extract_data)
+                .collect::<Result<_, _>>()?,
+            manifest,
+            raw_manifest: manifest_data,
+        })
+    }
+}
+
+// Image bytes the updater pulls in object-sized pieces, so a firmware image never has
+// to be resident in memory as a whole. `open` restarts from the first byte, which the
+// updater only needs when the device reports an offset behind what was sent.
+pub trait ImageSource {
+    fn len(&self) -> usize;
+    fn open(&mut self) -> io::Result<Box<dyn Read + '_>>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ImageSource for &[u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn open(&mut self) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(*self))
+    }
+}
+
+// A package whose images are decompressed from the zip as they are sent rather than
+// extracted up front. Only the manifest and the (small) init packets are held in memory.
+pub struct StreamingArchive<R> {
+    zip: ZipArchive<R>,
+    manifest_dir: String,
+    gunzip: bool,
+    pub manifest: ManifestInfo,
+}
+
+impl<R: Read + Seek> StreamingArchive<R> {
+    pub fn new(reader: R) -> Result<StreamingArchive<R>, ArchiveError> {
+        Self::new_with_gunzip(reader, true)
+    }
+
+    // See FirmwareArchive::new_with_gunzip.
+    pub fn new_with_gunzip(reader: R, gunzip: bool) -> Result<StreamingArchive<R>, ArchiveError> {
+        let mut zip = ZipArchive::new(reader)?;
+        let manifest_path = find_manifest(&zip)?;
+        let manifest_dir = manifest_path[..manifest_path.len() - "manifest.json".len()].to_string();
+        let manifest_data = String::from_utf8(zip.read(&manifest_path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let manifest = parse_manifest(&manifest_data)?;
+
+        for firmware in manifest.firmware() {
+            for name in &[&firmware.bin_file, &firmware.dat_file] {
+                let path = format!("{}{}", manifest_dir, name);
+                if !zip.contains(&path) {
+                    return Err(ArchiveError::MissingEntry(path));
+                }
+            }
+        }
+
+        Ok(StreamingArchive {
+            zip,
+            manifest_dir,
+            gunzip,
+            manifest,
+        })
+    }
+
+    // Same selection and order as `FirmwareArchive::images`.
+    pub fn images(&self) -> Vec<(ImageKind, Firmware)> {
+        let mut images = vec![];
+
+        if let Some(softdevice_bootloader) = &self.manifest.softdevice_bootloader {
+            images.push((ImageKind::SoftdeviceBootloader, softdevice_bootloader.clone()));
+        } else if let Some(bootloader) = &self.manifest.bootloader {
+            images.push((ImageKind::Bootloader, bootloader.clone()));
+        }
+
+        for application in &self.manifest.applications {
+            images.push((ImageKind::Application, application.clone()));
+        }
+
+        images
+    }
+
+    pub fn init_packet(&mut self, firmware: &Firmware) -> Result<Vec<u8>, ArchiveError> {
+        let dat = self.zip.read(&format!("{}{}", self.manifest_dir, firmware.dat_file))?;
+        if !self.gunzip {
+            return Ok(dat);
+        }
+        Ok(gunzip_if_compressed(dat)?)
+    }
+
+    pub fn image(&mut self, firmware: &Firmware) -> Result<ZipEntrySource<'_, R>, ArchiveError> {
+        let name = format!("{}{}", self.manifest_dir, firmware.bin_file);
+
+        let (gzipped, len) = {
+            let mut entry = self.zip.by_name(&name)?;
+            let size = entry.size() as usize;
+            let mut magic = [0u8; 2];
+            let gzipped = self.gunzip && entry.read_exact(&mut magic).is_ok() && magic == [0x1F, 0x8B];
+            drop(entry);
+
+            if gzipped {
+                // The decompressed size isn't recorded anywhere, so measure it.
+                let len = io::copy(&mut GzDecoder::new(self.zip.by_name(&name)?), &mut io::sink())?;
+                (true, len as usize)
+            } else {
+                (false, size)
+            }
+        };
+
+        Ok(ZipEntrySource {
+            zip: &mut self.zip,
+            name,
+            gzipped,
+            len,
+        })
+    }
+}
+
+pub struct ZipEntrySource<'a, R> {
+    zip: &'a mut ZipArchive<R>,
+    name: String,
+    gzipped: bool,
+    len: usize,
+}
+
+impl<R: Read + Seek> ImageSource for ZipEntrySource<'_, R> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn open(&mut self) -> io::Result<Box<dyn Read + '_>> {
+        let entry = self.zip.by_name(&self.name).map_err(io::Error::from)?;
+        if self.gzipped {
+            Ok(Box::new(GzDecoder::new(entry)))
+        } else {
+            Ok(Box::new(entry))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ArchiveBuilder {
+    bootloader: Option<FirmwareData>,
+    softdevice_bootloader: Option<FirmwareData>,
+    applications: Vec<FirmwareData>,
+}
+
+impl ArchiveBuilder {
+    pub fn new() -> ArchiveBuilder {
+        ArchiveBuilder::default()
+    }
+
+    // Each call adds another application image, flashed in the order added.
+    pub fn application(mut self, bin: Vec<u8>, dat: Vec<u8>) -> ArchiveBuilder {
+        self.applications.push(FirmwareData::new(bin, dat));
+        self
+    }
+
+    pub fn bootloader(mut self, bin: Vec<u8>, dat: Vec<u8>) -> ArchiveBuilder {
+        self.bootloader = Some(FirmwareData::new(bin, dat));
+        self
+    }
+
+    pub fn softdevice_bootloader(mut self, bin: Vec<u8>, dat: Vec<u8>) -> ArchiveBuilder {
+        self.softdevice_bootloader = Some(FirmwareData::new(bin, dat));
+        self
+    }
+
+    pub fn write_to<W: Write + Seek>(self, writer: W) -> Result<(), ArchiveError> {
+        let mut zip = ZipWriter::new(writer);
+        let mut manifest = ManifestInfo::default();
+
+        if let Some(bootloader) = self.bootloader {
+            manifest.bootloader = Some(write_image(&mut zip, "bootloader", bootloader)?);
+        }
+        if let Some(softdevice_bootloader) = self.softdevice_bootloader {
+            manifest.softdevice_bootloader =
+                Some(write_image(&mut zip, "sd_bl", softdevice_bootloader)?);
+        }
+        for (index, application) in self.applications.into_iter().enumerate() {
+            let name = match index {
+                0 => "application".to_string(),
+                n => format!("application_{}", n + 1),
+            };
+            manifest.applications.push(write_image(&mut zip, &name, application)?);
+        }
+
+        zip.start_file("manifest.json", FileOptions::default())?;
+        serde_json::to_writer_pretty(&mut zip, &Manifest { manifest })?;
+        zip.finish()?;
+        Ok(())
+    }
+}
+
+fn write_image<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    name: &str,
+    image: FirmwareData,
+) -> Result<Firmware, ArchiveError> {
+    let firmware = Firmware {
+        bin_file: format!("{}.bin", name),
+        dat_file: format!("{}.dat", name),
+    };
+    zip.start_file(firmware.bin_file.as_str(), FileOptions::default())?;
+    zip.write_all(image.bin())?;
+    zip.start_file(firmware.dat_file.as_str(), FileOptions::default())?;
+    zip.write_all(&image.dat)?;
+    Ok(firmware)
+}
+
+// Where the manifest and the files it names are read from: a zip or a plain directory.
+trait ArchiveSource {
+    fn contains(&self, name: &str) -> bool;
+    fn read(&mut self, name: &str) -> Result<Vec<u8>, ArchiveError>;
+}
+
+impl<R: Read + Seek> ArchiveSource for ZipArchive<R> {
+    fn contains(&self, name: &str) -> bool {
+        self.file_names().any(|entry| entry == name)
+    }
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>, ArchiveError> {
+        let mut data = vec![];
+        self.by_name(name)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+struct DirSource<'a>(&'a Path);
+
+impl ArchiveSource for DirSource<'_> {
+    fn contains(&self, name: &str) -> bool {
+        self.0.join(name).is_file()
+    }
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>, ArchiveError> {
+        Ok(fs::read(self.0.join(name))?)
+    }
+}
+
+// Some tools capitalize the manifest name or nest the whole package in a folder, so
+// match case-insensitively and take the shallowest candidate.
+fn find_manifest<R: Read + Seek>(archive: &ZipArchive<R>) -> Result<String, ArchiveError> {
+    archive
+        .file_names()
+        .filter(|name| {
+            let file_name = name.rsplit('/').next().unwrap_or(name);
+            file_name.eq_ignore_ascii_case("manifest.json")
+        })
+        .min_by_key(|name| name.matches('/').count())
+        .map(String::from)
+        .ok_or_else(|| ArchiveError::MissingManifest(archive.file_names().map(String::from).collect()))
+}
+
+fn gunzip_if_compressed(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    if !data.starts_with(&[0x1F, 0x8B]) {
+        return Ok(data);
+    }
+
+    let mut decompressed = vec![];
+    GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use zip::CompressionMethod;
+
+    use super::*;
+
+    const MANIFEST: &str = r#"{"manifest":{"application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#;
+
+    fn zip_bytes(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in entries {
+            let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn stored_zip(entries: &[(&str, &[u8])]) -> ZipArchive<Cursor<Vec<u8>>> {
+        ZipArchive::new(Cursor::new(zip_bytes(entries))).unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn crc_is_shared_across_threads() {
+        let image = FirmwareData::new(vec![0xAA; 64], vec![]);
+        let crc = std::thread::scope(|scope| scope.spawn(|| image.crc32()).join().unwrap());
+
+        assert_eq!(crc, crc32::checksum_ieee(image.bin()));
+        assert_eq!(image.crc32(), crc);
+    }
+
+    #[test]
+    fn gzipped_entries_are_decompressed() {
+        let bin = gzip(&[0xAA; 64]);
+        let zip = stored_zip(&[
+            ("manifest.json", MANIFEST.as_bytes()),
+            ("app.bin", &bin),
+            ("app.dat", &[0x12, 0x34]),
+        ]);
+
+        let application = FirmwareArchive::from_zip(zip, true).unwrap().applications.remove(0);
+
+        assert_eq!(application.bin, vec![0xAA; 64]);
+        assert_eq!(application.dat, vec![0x12, 0x34]);
+    }
+
+    #[test]
+    fn gunzip_can_be_disabled() {
+        let bin = gzip(&[0xAA; 64]);
+        let zip = stored_zip(&[
+            ("manifest.json", MANIFEST.as_bytes()),
+            ("app.bin", &bin),
+            ("app.dat", &[0x12, 0x34]),
+        ]);
+
+        let application = FirmwareArchive::from_zip(zip, false).unwrap().applications.remove(0);
+
+        assert_eq!(application.bin, bin);
+    }
+
+    #[test]
+    fn raw_manifest_is_kept() {
+        let zip = stored_zip(&[
+            ("manifest.json", MANIFEST.as_bytes()),
+            ("app.bin", &[0xAA; 4]),
+            ("app.dat", &[0x12, 0x34]),
+        ]);
+
+        assert_eq!(FirmwareArchive::from_zip(zip, true).unwrap().manifest_json(), MANIFEST);
+    }
+
+    #[test]
+    fn reads_from_in_memory_reader() {
+        let bytes = zip_bytes(&[
+            ("manifest.json", MANIFEST.as_bytes()),
+            ("app.bin", &[0xAA; 4]),
+            ("app.dat", &[0x12, 0x34]),
+        ]);
+
+        let archive = FirmwareArchive::from_reader(Cursor::new(bytes)).unwrap();
+
+        assert_eq!(archive.applications[0].bin, vec![0xAA; 4]);
+        assert!(archive.bootloader.is_none());
+    }
+
+    #[test]
+    fn reads_all_slots_from_bytes() {
+        let manifest = r#"{"manifest":{
+            "bootloader":{"bin_file":"bl.bin","dat_file":"bl.dat"},
+            "softdevice_bootloader":{"bin_file":"sd_bl.bin","dat_file":"sd_bl.dat"},
+            "application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#;
+        let bytes = zip_bytes(&[
+            ("manifest.json", manifest.as_bytes()),
+            ("bl.bin", &[0x01]),
+            ("bl.dat", &[0x02]),
+            ("sd_bl.bin", &[0x03]),
+            ("sd_bl.dat", &[0x04]),
+            ("app.bin", &[0x05]),
+            ("app.dat", &[0x06]),
+        ]);
+
+        let archive = FirmwareArchive::from_bytes(&bytes).unwrap();
+        drop(bytes);
+
+        let bootloader = archive.bootloader.unwrap();
+        assert_eq!((bootloader.bin, bootloader.dat), (vec![0x01], vec![0x02]));
+        let softdevice_bootloader = archive.softdevice_bootloader.unwrap();
+        assert_eq!((softdevice_bootloader.bin, softdevice_bootloader.dat), (vec![0x03], vec![0x04]));
+        let application = &archive.applications[0];
+        assert_eq!((&application.bin[..], &application.dat[..]), (&[0x05][..], &[0x06][..]));
+    }
+
+    #[test]
+    fn manifest_declarations_are_exposed() {
+        let zip = stored_zip(&[
+            ("manifest.json", MANIFEST.as_bytes()),
+            ("app.bin", &[0xAA; 4]),
+            ("app.dat", &[0x12, 0x34]),
+        ]);
+
+        let archive = FirmwareArchive::from_zip(zip, true).unwrap();
+
+        let application = &archive.manifest.applications[0];
+        assert_eq!(application.bin_file, "app.bin");
+        assert_eq!(application.dat_file, "app.dat");
+        assert!(archive.manifest.bootloader.is_none());
+        assert_eq!(archive.raw_manifest, MANIFEST);
+    }
+
+    #[test]
+    fn missing_entry_is_reported() {
+        let zip = stored_zip(&[("manifest.json", MANIFEST.as_bytes()), ("app.bin", &[0xAA; 4])]);
+
+        match FirmwareArchive::from_zip(zip, true) {
+            Err(ArchiveError::MissingEntry(name)) => assert_eq!(name, "app.dat"),
+            _ => panic!("expected MissingEntry"),
+        }
+    }
+
+    const LEGACY_MANIFEST: &str = r#"{"manifest":{
+        "application":{"bin_file":"app.bin","dat_file":"app.dat",
+            "init_packet_data":{"application_version":4294967295,"device_revision":65535,
+                "device_type":65535,"firmware_crc16":46315,"softdevice_req":[65534]}},
+        "dfu_version":0.5}}"#;
+
+    #[test]
+    fn parses_current_manifest() {
+        let manifest = parse_manifest(MANIFEST).unwrap();
+
+        assert_eq!(manifest.applications[0].bin_file, "app.bin");
+    }
+
+    #[test]
+    fn parses_legacy_manifest() {
+        let zip = stored_zip(&[
+            ("manifest.json", LEGACY_MANIFEST.as_bytes()),
+            ("app.bin", &[0xAA; 4]),
+            ("app.dat", &[0x12, 0x34]),
+        ]);
+
+        let archive = FirmwareArchive::from_zip(zip, true).unwrap();
+
+        assert_eq!(archive.manifest.applications[0].dat_file, "app.dat");
+        assert_eq!(archive.applications[0].bin, vec![0xAA; 4]);
+    }
+
+    #[test]
+    fn legacy_manifest_rejects_unknown_images() {
+        let manifest = r#"{"manifest":{"softdevice":{"bin_file":"sd.bin","dat_file":"sd.dat"},"dfu_version":0.5}}"#;
+
+        assert!(matches!(parse_manifest(manifest), Err(ArchiveError::JsonError(_))));
+    }
+
+    #[test]
+    fn reads_from_directory() {
+        let dir = std::env::temp_dir().join(format!("nrf_dfu_from_dir_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("manifest.json"), MANIFEST).unwrap();
+        fs::write(dir.join("app.bin"), [0xAA; 4]).unwrap();
+        fs::write(dir.join("app.dat"), [0x12, 0x34]).unwrap();
+
+        let archive = FirmwareArchive::from_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let application = archive.unwrap().applications.remove(0);
+        assert_eq!(application.bin, vec![0xAA; 4]);
+        assert_eq!(application.dat, vec![0x12, 0x34]);
+    }
+
+    #[test]
+    fn builder_round_trips() {
+        let mut bytes = Cursor::new(vec![]);
+        ArchiveBuilder::new()
+            .softdevice_bootloader(vec![0x01; 8], vec![0x02; 2])
+            .application(vec![0x03; 16], vec![0x04; 2])
+            .write_to(&mut bytes)
+            .unwrap();
+
+        let archive = FirmwareArchive::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+
+        assert!(archive.bootloader.is_none());
+        let softdevice_bootloader = archive.softdevice_bootloader.unwrap();
+        assert_eq!(softdevice_bootloader.bin, vec![0x01; 8]);
+        assert_eq!(softdevice_bootloader.dat, vec![0x02; 2]);
+        let application = &archive.applications[0];
+        assert_eq!(application.bin, vec![0x03; 16]);
+        assert_eq!(application.dat, vec![0x04; 2]);
+        assert_eq!(archive.manifest.applications[0].bin_file, "application.bin");
+    }
+
+    #[test]
+    fn multiple_applications_are_extracted() {
+        let manifest = r#"{"manifest":{"application":[
+            {"bin_file":"app.bin","dat_file":"app.dat"},
+            {"bin_file":"net.bin","dat_file":"net.dat"}]}}"#;
+        let zip = stored_zip(&[
+            ("manifest.json", manifest.as_bytes()),
+            ("app.bin", &[0xAA; 4]),
+            ("app.dat", &[0x01]),
+            ("net.bin", &[0xBB; 8]),
+            ("net.dat", &[0x02]),
+        ]);
+
+        let archive = FirmwareArchive::from_zip(zip, true).unwrap();
+
+        assert_eq!(archive.applications.len(), 2);
+        assert_eq!(archive.applications[0].bin, vec![0xAA; 4]);
+        assert_eq!(archive.applications[1].bin, vec![0xBB; 8]);
+        assert_eq!(archive.applications[1].dat, vec![0x02]);
+        let kinds: Vec<_> = archive.images().into_iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, vec![ImageKind::Application, ImageKind::Application]);
+    }
+
+    #[test]
+    fn builder_writes_multiple_applications() {
+        let mut bytes = Cursor::new(vec![]);
+        ArchiveBuilder::new()
+            .application(vec![0x03; 16], vec![0x04; 2])
+            .application(vec![0x05; 16], vec![0x06; 2])
+            .write_to(&mut bytes)
+            .unwrap();
+
+        let archive = FirmwareArchive::from_reader(Cursor::new(bytes.into_inner())).unwrap();
+
+        assert_eq!(archive.applications[1].bin, vec![0x05; 16]);
+        assert_eq!(archive.manifest.applications[1].bin_file, "application_2.bin");
+        assert!(archive.manifest_json().contains('['));
+    }
+
+    #[test]
+    fn streaming_archive_reads_entries_lazily() {
+        let bin = gzip(&[0xAA; 5000]);
+        let bytes = zip_bytes(&[
+            ("manifest.json", MANIFEST.as_bytes()),
+            ("app.bin", &bin),
+            ("app.dat", &gzip(&[0x12, 0x34])),
+        ]);
+
+        let mut archive = StreamingArchive::new(Cursor::new(bytes)).unwrap();
+        let images = archive.images();
+        let (kind, firmware) = &images[0];
+
+        assert_eq!(*kind, ImageKind::Application);
+        assert_eq!(archive.init_packet(firmware).unwrap(), vec![0x12, 0x34]);
+
+        let mut source = archive.image(firmware).unwrap();
+        assert_eq!(source.len(), 5000);
+        for _ in 0..2 {
+            let mut data = vec![];
+            source.open().unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(data, vec![0xAA; 5000]);
+        }
+    }
+
+    #[test]
+    fn every_loader_can_keep_gzipped_entries() {
+        let bin = gzip(&[0xAA; 64]);
+        let dat = gzip(&[0x12, 0x34]);
+        let bytes = zip_bytes(&[("manifest.json", MANIFEST.as_bytes()), ("app.bin", &bin), ("app.dat", &dat)]);
+
+        let archive = FirmwareArchive::from_bytes_with_gunzip(&bytes, false).unwrap();
+        assert_eq!((archive.applications[0].bin(), &archive.applications[0].dat[..]), (&bin[..], &dat[..]));
+
+        let mut archive = StreamingArchive::new_with_gunzip(Cursor::new(bytes), false).unwrap();
+        let (_, firmware) = archive.images().remove(0);
+        assert_eq!(archive.init_packet(&firmware).unwrap(), dat);
+        let mut data = vec![];
+        archive.image(&firmware).unwrap().open().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, bin);
+    }
+
+    #[test]
+    fn summary_reports_size_and_crc() {
+        let archive = ArchiveBuilder::new()
+            .bootloader(vec![0x01; 8], vec![0x02; 2])
+            .application(vec![0x03; 16], vec![0x04; 2]);
+        let mut bytes = Cursor::new(vec![]);
+        archive.write_to(&mut bytes).unwrap();
+        let archive = FirmwareArchive::from_bytes(bytes.get_ref()).unwrap();
+
+        assert_eq!(
+            archive.summary(),
+            vec![
+                ImageSummary { kind: ImageKind::Bootloader, size: 8, crc32: crc32::checksum_ieee(&[0x01; 8]) },
+                ImageSummary { kind: ImageKind::Application, size: 16, crc32: crc32::checksum_ieee(&[0x03; 16]) },
+            ]
+        );
+    }
+
+    #[test]
+    fn selected_images_honour_module_selection() {
+        let mut bytes = Cursor::new(vec![]);
+        ArchiveBuilder::new()
+            .softdevice_bootloader(vec![0x01; 8], vec![0x02; 2])
+            .bootloader(vec![0x03; 8], vec![0x04; 2])
+            .application(vec![0x05; 16], vec![0x06; 2])
+            .write_to(&mut bytes)
+            .unwrap();
+        let archive = FirmwareArchive::from_bytes(bytes.get_ref()).unwrap();
+        let kinds = |modules| -> Vec<ImageKind> {
+            archive.selected_images(modules).into_iter().map(|(kind, _)| kind).collect()
+        };
+
+        assert_eq!(kinds(ModuleSelection::APPLICATION), vec![ImageKind::Application]);
+        assert_eq!(
+            kinds(ModuleSelection::BOOTLOADER | ModuleSelection::APPLICATION),
+            vec![ImageKind::Bootloader, ImageKind::Application]
+        );
+        assert_eq!(
+            kinds(ModuleSelection::ALL),
+            vec![ImageKind::SoftdeviceBootloader, ImageKind::Application]
+        );
+    }
+
+    #[test]
+    fn nested_manifest_is_found() {
+        let zip = stored_zip(&[
+            ("package/MANIFEST.json", MANIFEST.as_bytes()),
+            ("package/app.bin", &[0xAA; 4]),
+            ("package/app.dat", &[0x12, 0x34]),
+        ]);
+
+        let application = FirmwareArchive::from_zip(zip, true).unwrap().applications.remove(0);
+
+        assert_eq!(application.bin, vec![0xAA; 4]);
+    }
+
+    #[test]
+    fn missing_manifest_lists_entries() {
+        let zip = stored_zip(&[("app.bin", &[0xAA; 4]), ("app.dat", &[0x12, 0x34])]);
+
+        match FirmwareArchive::from_zip(zip, true) {
+            Err(ArchiveError::MissingManifest(entries)) => {
+                assert_eq!(entries.len(), 2);
+                assert!(entries.contains(&"app.bin".to_string()));
+            }
+            _ => panic!("expected MissingManifest"),
         }
     }
 }
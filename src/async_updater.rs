@@ -0,0 +1,548 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{sleep, timeout};
+
+use crate::archive::{FirmwareArchive, ImageKind};
+use crate::codec::AsyncDfuCodec;
+use crate::dfu::{DfuError, DfuRequest, DfuResponse, ObjectType};
+use crate::protocol::*;
+use crate::updater::{chunk_size_for_mtu, CrcAlgorithm, Error, ResetMode};
+
+// The async counterpart of NordicDevice, for transports that live on a tokio runtime.
+#[allow(async_fn_in_trait)]
+pub trait AsyncNordicDevice: AsyncRead + AsyncWrite + Unpin {
+    type Codec: AsyncDfuCodec;
+    async fn reset(&mut self, mode: ResetMode);
+
+    async fn drain_input(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // See NordicDevice::reconnect.
+    async fn reconnect(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn max_frame(&self) -> usize {
+        usize::MAX
+    }
+}
+
+// Runs the same object transfer as Updater without blocking the runtime. Pipelining,
+// cancellation, progress and two-phase commit are only available on Updater for now.
+pub struct AsyncUpdater<'a, T: AsyncNordicDevice> {
+    comm: &'a mut T,
+    prn: u16,
+    chunk_size: usize,
+    force: bool,
+    ping_attempts: u32,
+    ping_retry_delay: Duration,
+    crc: CrcAlgorithm,
+    bootloader_reset_delay: Duration,
+    app_reset_delay: Duration,
+    chunk_size_override: Option<usize>,
+    request_timeout: Duration,
+    execute_timeout: Duration,
+    cancel: Option<Arc<AtomicBool>>,
+    scratch: Vec<u8>,
+}
+
+impl<'a, T: AsyncNordicDevice> AsyncUpdater<'a, T> {
+    /// See [`Updater::new`](crate::updater::Updater::new).
+    pub fn new(comm: &'a mut T, force: bool) -> Self {
+        Self {
+            comm,
+            prn: 3,
+            chunk_size: 0,
+            force,
+            ping_attempts: 3,
+            ping_retry_delay: Duration::from_millis(100),
+            crc: CrcAlgorithm::Ieee,
+            bootloader_reset_delay: Duration::from_millis(1000),
+            app_reset_delay: Duration::from_millis(500),
+            chunk_size_override: None,
+            request_timeout: Duration::from_secs(5),
+            execute_timeout: Duration::from_secs(30),
+            cancel: None,
+            scratch: vec![],
+        }
+    }
+
+    /// See [`Updater::set_cancel_flag`](crate::updater::Updater::set_cancel_flag). Prefer this
+    /// to dropping the update future, which would leave the device holding a half-written object.
+    pub fn set_cancel_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.cancel = Some(flag);
+    }
+
+    fn cancel_requested(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|flag| flag.load(Ordering::SeqCst))
+    }
+
+    async fn pause(&mut self, object_type: ObjectType) -> Result<Error, Error> {
+        let GetCrcResponse { offset, crc } = self.request(GetCrcRequest).await?;
+        self.request(AbortRequest).await?;
+        Ok(Error::Cancelled {
+            offset,
+            crc,
+            object_type,
+        })
+    }
+
+    /// See [`Updater::set_timeouts`](crate::updater::Updater::set_timeouts).
+    pub fn set_timeouts(&mut self, request_timeout: Duration, execute_timeout: Duration) {
+        self.request_timeout = request_timeout;
+        self.execute_timeout = execute_timeout;
+    }
+
+    /// See [`Updater::set_chunk_size_override`](crate::updater::Updater::set_chunk_size_override).
+    pub fn set_chunk_size_override(&mut self, chunk_size: Option<usize>) {
+        self.chunk_size_override = chunk_size.filter(|&chunk_size| chunk_size > 0);
+    }
+
+    /// See [`Updater::set_reset_delays`](crate::updater::Updater::set_reset_delays).
+    pub fn set_reset_delays(&mut self, bootloader_reset_delay: Duration, app_reset_delay: Duration) {
+        self.bootloader_reset_delay = bootloader_reset_delay;
+        self.app_reset_delay = app_reset_delay;
+    }
+
+    /// See [`Updater::set_prn`](crate::updater::Updater::set_prn).
+    pub fn set_prn(&mut self, prn: u16) {
+        self.prn = prn;
+    }
+
+    /// See [`Updater::set_ping_retries`](crate::updater::Updater::set_ping_retries).
+    pub fn set_ping_retries(&mut self, attempts: u32, delay: Duration) {
+        self.ping_attempts = attempts.max(1);
+        self.ping_retry_delay = delay;
+    }
+
+    /// See [`Updater::set_crc_algorithm`](crate::updater::Updater::set_crc_algorithm).
+    pub fn set_crc_algorithm(&mut self, crc: CrcAlgorithm) {
+        self.crc = crc;
+    }
+
+    async fn request<'de, Request: DfuRequest<'de>>(&mut self, request: Request) -> Result<Request::Response, Error> {
+        let payload = request.serialize()?;
+        T::Codec::encoded_write(self.comm, &[&[Request::REQUEST_OPCODE], &payload]).await?;
+        self.read_response::<Request>().await
+    }
+
+    async fn read_response<'de, Request: DfuRequest<'de>>(&mut self) -> Result<Request::Response, Error> {
+        if !Request::Response::EXPECTED {
+            return Request::Response::from_frame::<Request>(vec![]);
+        }
+        let deadline = if Request::REQUEST_OPCODE == ObjectExecuteRequest::REQUEST_OPCODE {
+            self.execute_timeout
+        } else {
+            self.request_timeout
+        };
+        let response = timeout(deadline, T::Codec::decoded_read(self.comm))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        Request::Response::from_frame::<Request>(response)
+    }
+
+    // Encodes the chunk straight from the image into the reused scratch buffer; going
+    // through `request` would copy it into a serialized payload first.
+    async fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        let bufs: [&[u8]; 2] = [&[<ObjectWriteRequest>::REQUEST_OPCODE], chunk];
+        T::Codec::encoded_write_into(self.comm, &bufs, &mut self.scratch).await?;
+        Ok(())
+    }
+
+    async fn write_object(
+        &mut self,
+        object_type: ObjectType,
+        mut offset: usize,
+        mut object_crc: u32,
+        data: &[u8],
+    ) -> Result<u32, Error> {
+        let mut prn_count = 0;
+
+        for chunk in data.chunks(self.chunk_size) {
+            object_crc = self.crc.update(object_crc, chunk);
+            offset += chunk.len();
+
+            let receipt_due = self.prn > 0 && {
+                prn_count += 1;
+                prn_count == self.prn
+            };
+
+            if receipt_due {
+                prn_count = 0;
+                self.write_chunk(chunk).await?;
+                let ObjectWriteResponse { offset: reported, crc } = self.read_response::<ObjectWriteRequest>().await?;
+                if reported as usize != offset {
+                    return Err(Error::OffsetMismatch {
+                        reported: reported as usize,
+                        expected: offset,
+                    });
+                }
+                if crc != object_crc {
+                    return Err(Error::CrcMismatch);
+                }
+            } else {
+                self.write_chunk(chunk).await?;
+            }
+
+            if self.cancel_requested() {
+                return Err(self.pause(object_type).await?);
+            }
+        }
+
+        Ok(object_crc)
+    }
+
+    async fn transfer_object(&mut self, object_type: ObjectType, data: &[u8]) -> Result<(), Error> {
+        let ObjectSelectResponse {
+            max_size,
+            offset,
+            crc,
+        } = self.request(ObjectSelectRequest { object_type }).await?;
+
+        // See Updater::transfer_empty.
+        if data.is_empty() {
+            self.request(ObjectCreateRequest {
+                object_type,
+                object_size: 0,
+            })
+            .await?;
+            self.request(ObjectExecuteRequest).await?;
+            return Ok(());
+        }
+
+        let object_max_size = max_size as usize;
+        let mut object_offset = offset as usize;
+        let mut object_crc = crc;
+
+        if self.force {
+            object_offset = 0;
+            object_crc = 0;
+        }
+
+        if object_offset > data.len() {
+            return Err(Error::OffsetOutOfSync {
+                reported: object_offset,
+                sent: 0,
+            });
+        }
+
+        let mut prefix_crc = self.crc.checksum(&data[..object_offset]);
+        // See Updater::transfer_source.
+        if object_crc != prefix_crc {
+            if object_type == ObjectType::Data {
+                return Err(Error::ResumeMismatch { offset: object_offset });
+            }
+            object_offset = 0;
+            object_crc = 0;
+            prefix_crc = 0;
+        }
+
+        loop {
+            if (object_offset > 0 && object_offset.is_multiple_of(object_max_size))
+                || (object_offset == data.len() && object_crc == prefix_crc)
+            {
+                self.request(ObjectExecuteRequest).await?;
+
+                if object_offset == data.len() {
+                    break;
+                }
+            }
+
+            let object_end = (object_offset - (object_offset % object_max_size) + object_max_size).min(data.len());
+
+            if object_offset.is_multiple_of(object_max_size) || object_crc != prefix_crc {
+                self.request(ObjectCreateRequest {
+                    object_type,
+                    object_size: (object_end - object_offset) as u32,
+                })
+                .await?;
+            }
+
+            object_crc = self
+                .write_object(object_type, object_offset, object_crc, &data[object_offset..object_end])
+                .await?;
+
+            let GetCrcResponse { offset, crc } = self.request(GetCrcRequest).await?;
+            let reported = offset as usize;
+            if reported > object_end || reported < object_offset {
+                return Err(Error::OffsetOutOfSync {
+                    reported,
+                    sent: object_end,
+                });
+            }
+            if reported == object_end {
+                prefix_crc = self.crc.update(prefix_crc, &data[object_offset..object_end]);
+            } else {
+                // The device kept only part of the object, so its prefix is one we haven't summed.
+                prefix_crc = self.crc.checksum(&data[..reported]);
+            }
+            object_offset = reported;
+            if crc != object_crc {
+                return Err(Error::CrcMismatch);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn ping_handshake(&mut self) -> Result<(), Error> {
+        let mut attempt = 1;
+        loop {
+            self.comm.drain_input().await?;
+            let err = match self.request(PingRequest { id: 0x7F }).await {
+                Ok(PingResponse { id: 0x7F }) => return Ok(()),
+                Ok(PingResponse { .. }) => Error::PingMismatch,
+                Err(Error::DfuError(DfuError::OpcodeNotSupported)) => return Ok(()),
+                Err(err) => err,
+            };
+
+            if attempt >= self.ping_attempts {
+                return match err {
+                    Error::IOError(_)
+                    | Error::Timeout
+                    | Error::UnexpectedResponse(_)
+                    | Error::DfuError(DfuError::InvalidOpcode) => Err(Error::NoBootloaderResponse),
+                    err => Err(err),
+                };
+            }
+            attempt += 1;
+            sleep(self.ping_retry_delay).await;
+        }
+    }
+
+    async fn negotiate(&mut self) -> Result<(), Error> {
+        self.ping_handshake().await?;
+
+        self.request(SetReceiptNotifyRequest { target: self.prn }).await?;
+
+        self.chunk_size = match self.request(GetMtuRequest).await {
+            Ok(GetMtuResponse { mtu }) => chunk_size_for_mtu(mtu)?,
+            Err(Error::DfuError(DfuError::OpcodeNotSupported)) => 244,
+            Err(err) => return Err(err),
+        };
+        if let Some(chunk_size) = self.chunk_size_override {
+            self.chunk_size = chunk_size;
+        }
+        self.chunk_size = self.chunk_size.min(self.comm.max_frame()).max(1);
+
+        Ok(())
+    }
+
+    async fn update_module(&mut self, init: &[u8], firmware: &[u8]) -> Result<(), Error> {
+        self.negotiate().await?;
+
+        self.transfer_object(ObjectType::Command, init).await?;
+
+        match self.transfer_object(ObjectType::Data, firmware).await {
+            Err(Error::ResumeMismatch { .. }) => {
+                let force = std::mem::replace(&mut self.force, true);
+                let mut result = self.transfer_object(ObjectType::Command, init).await;
+                if result.is_ok() {
+                    result = self.transfer_object(ObjectType::Data, firmware).await;
+                }
+                self.force = force;
+                result
+            }
+            result => result,
+        }
+    }
+
+    /// See [`Updater::update`](crate::updater::Updater::update).
+    pub async fn update(&mut self, firmware: &FirmwareArchive) -> Result<(), Error> {
+        for (kind, image) in firmware.images() {
+            match self.update_module(&image.dat, image.bin()).await {
+                Ok(()) => {}
+                Err(err @ Error::Cancelled { .. }) => return Err(err),
+                Err(err) => {
+                    self.request(AbortRequest).await?;
+                    return Err(err);
+                }
+            }
+
+            match kind {
+                ImageKind::SoftdeviceBootloader => {
+                    sleep(self.bootloader_reset_delay).await;
+                    self.comm.reset(ResetMode::Bootloader).await;
+                    self.comm.reconnect().await?;
+                }
+                ImageKind::Bootloader => {
+                    sleep(self.app_reset_delay).await;
+                    self.comm.reset(ResetMode::Bootloader).await;
+                    self.comm.reconnect().await?;
+                }
+                ImageKind::Application => sleep(self.app_reset_delay).await,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use crc::crc32;
+    use tokio::io::ReadBuf;
+
+    use super::*;
+    use crate::archive::{FirmwareData, ManifestInfo};
+    use crate::slip::SlipCodec;
+
+    struct ScriptedDevice {
+        responses: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+        resets: Vec<ResetMode>,
+    }
+
+    impl ScriptedDevice {
+        fn new(responses: &[Vec<u8>]) -> ScriptedDevice {
+            ScriptedDevice {
+                responses: Cursor::new(responses.concat()),
+                written: vec![],
+                resets: vec![],
+            }
+        }
+    }
+
+    impl AsyncRead for ScriptedDevice {
+        // Once the script runs out the device goes quiet rather than hitting EOF.
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut ReadBuf) -> Poll<std::io::Result<()>> {
+            match std::io::Read::read(&mut self.responses, buf.initialize_unfilled())? {
+                0 => Poll::Pending,
+                n => {
+                    buf.advance(n);
+                    Poll::Ready(Ok(()))
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for ScriptedDevice {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncNordicDevice for ScriptedDevice {
+        type Codec = SlipCodec;
+
+        async fn reset(&mut self, mode: ResetMode) {
+            self.resets.push(mode);
+        }
+    }
+
+    fn response(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        [&[0x60, opcode, 0x01], payload, &[0xC0]].concat()
+    }
+
+    fn select_response(max_size: u32, offset: u32, crc: u32) -> Vec<u8> {
+        response(0x06, &[max_size.to_le_bytes(), offset.to_le_bytes(), crc.to_le_bytes()].concat())
+    }
+
+    fn crc_response(offset: u32, crc: u32) -> Vec<u8> {
+        response(0x03, &[offset.to_le_bytes(), crc.to_le_bytes()].concat())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn updates_application() {
+        let init = [0x12u8; 8];
+        let bin = [0xA5u8; 24];
+        let mut device = ScriptedDevice::new(&[
+            response(0x09, &[0x7F]),
+            response(0x02, &[]),
+            response(0x07, &18u16.to_le_bytes()),
+            select_response(256, 0, 0),
+            response(0x01, &[]),
+            crc_response(8, crc32::checksum_ieee(&init)),
+            response(0x04, &[]),
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(24, crc32::checksum_ieee(&bin)),
+            crc_response(24, crc32::checksum_ieee(&bin)),
+            response(0x04, &[]),
+        ]);
+        let firmware = FirmwareArchive {
+            bootloader: None,
+            softdevice_bootloader: None,
+            applications: vec![FirmwareData::new(bin.to_vec(), init.to_vec())],
+            manifest: ManifestInfo::default(),
+            raw_manifest: String::new(),
+        };
+
+        AsyncUpdater::new(&mut device, false).update(&firmware).await.unwrap();
+
+        assert!(device.written.ends_with(&[0x03, 0xC0, 0x04, 0xC0]));
+        assert!(device.resets.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_missing_bootloader() {
+        let mut device = ScriptedDevice::new(&[vec![0x00]]);
+        let mut updater = AsyncUpdater::new(&mut device, false);
+        updater.set_ping_retries(1, Duration::from_millis(0));
+
+        assert!(matches!(updater.negotiate().await, Err(Error::NoBootloaderResponse)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_device_times_out() {
+        let mut device = ScriptedDevice::new(&[]);
+        let mut updater = AsyncUpdater::new(&mut device, false);
+
+        assert!(matches!(updater.request(GetCrcRequest).await, Err(Error::Timeout)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_aborts_after_chunk() {
+        let data = [0x5Au8; 16];
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(8, crc32::checksum_ieee(&data[..8])),
+        ]);
+
+        let mut updater = AsyncUpdater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.set_cancel_flag(Arc::new(AtomicBool::new(true)));
+
+        assert!(matches!(
+            updater.transfer_object(ObjectType::Data, &data).await,
+            Err(Error::Cancelled { offset: 8, object_type: ObjectType::Data, .. })
+        ));
+        assert!(device.written.ends_with(&[0x03, 0xC0, 0x0C, 0xC0]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn crc_offset_past_the_object_is_reported() {
+        let data = [0xA5u8; 16];
+        let mut device = ScriptedDevice::new(&[
+            select_response(8, 0, 0),
+            response(0x01, &[]),
+            crc_response(12, crc32::checksum_ieee(&data[..12])),
+        ]);
+
+        let mut updater = AsyncUpdater::new(&mut device, false);
+        updater.chunk_size = 8;
+
+        assert!(matches!(
+            updater.transfer_object(ObjectType::Data, &data).await,
+            Err(Error::OffsetOutOfSync { reported: 12, sent: 8 })
+        ));
+    }
+}
@@ -0,0 +1,333 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use btleplug::api::{
+    bleuuid::uuid_from_u16, BDAddr, Central, Peripheral as _, ScanFilter, ValueNotification, WriteType,
+};
+use btleplug::platform::{Adapter, Peripheral, PeripheralId};
+use futures::{FutureExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use uuid::Uuid;
+
+use crate::async_updater::AsyncNordicDevice;
+use crate::gatt::{is_packet, tagged_len, GattCodec};
+use crate::updater::ResetMode;
+
+// Nordic's Secure DFU service and its characteristics.
+pub const DFU_SERVICE: Uuid = uuid_from_u16(0xFE59);
+const CONTROL_POINT: Uuid = Uuid::from_u128(0x8EC90001_F315_4F60_9FB8_838830DAEA50);
+const PACKET: Uuid = Uuid::from_u128(0x8EC90002_F315_4F60_9FB8_838830DAEA50);
+// Buttonless DFU, without and with bond sharing.
+const BUTTONLESS: [Uuid; 2] = [
+    Uuid::from_u128(0x8EC90003_F315_4F60_9FB8_838830DAEA50),
+    Uuid::from_u128(0x8EC90004_F315_4F60_9FB8_838830DAEA50),
+];
+const ENTER_BOOTLOADER: u8 = 0x01;
+
+type Notifications = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
+fn ble_error(err: btleplug::Error) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+// A peripheral found by `BleDevice::list`. Applications with buttonless DFU advertise
+// the DFU service too, so this may be either; `BleDevice::open` connects to it.
+#[derive(Clone, Debug)]
+pub struct BleCandidate {
+    pub id: PeripheralId,
+    pub address: BDAddr,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+}
+
+// An AsyncNordicDevice over btleplug. Two background tasks shuttle GattCodec's tagged
+// frames to the Control Point and Packet characteristics and Control Point
+// notifications back; if a GATT write fails they stop, and the updater sees a timeout.
+//
+// Connected to an application instead of the bootloader, `reset(ResetMode::Bootloader)`
+// goes through the buttonless DFU service, and `reconnect` then scans for the
+// bootloader, which may advertise under a new address.
+pub struct BleDevice {
+    adapter: Adapter,
+    peripheral: Peripheral,
+    stream: DuplexStream,
+    tasks: Vec<JoinHandle<()>>,
+    max_write: usize,
+    scan_timeout: Duration,
+}
+
+impl BleDevice {
+    // Connects to the first peripheral found advertising the DFU service with its
+    // Control Point exposed, i.e. a device already in the bootloader.
+    pub async fn scan(adapter: Adapter, scan_timeout: Duration) -> std::io::Result<BleDevice> {
+        let peripheral = find_bootloader(&adapter, scan_timeout).await?;
+        let mut device = Self::connect(adapter, peripheral).await?;
+        device.scan_timeout = scan_timeout;
+        Ok(device)
+    }
+
+    // Scans for `scan_timeout` and returns every peripheral seen advertising the DFU
+    // service, without connecting to any of them.
+    pub async fn list(adapter: &Adapter, scan_timeout: Duration) -> std::io::Result<Vec<BleCandidate>> {
+        adapter
+            .start_scan(ScanFilter {
+                services: vec![DFU_SERVICE],
+            })
+            .await
+            .map_err(ble_error)?;
+        sleep(scan_timeout).await;
+        let peripherals = adapter.peripherals().await.map_err(ble_error);
+        let _ = adapter.stop_scan().await;
+
+        let mut candidates = vec![];
+        for peripheral in peripherals? {
+            let properties = match peripheral.properties().await.map_err(ble_error)? {
+                Some(properties) if properties.services.contains(&DFU_SERVICE) => properties,
+                _ => continue,
+            };
+            candidates.push(BleCandidate {
+                id: peripheral.id(),
+                address: properties.address,
+                name: properties.local_name,
+                rssi: properties.rssi,
+            });
+        }
+        Ok(candidates)
+    }
+
+    pub async fn open(adapter: Adapter, id: &PeripheralId) -> std::io::Result<BleDevice> {
+        let peripheral = adapter.peripheral(id).await.map_err(ble_error)?;
+        Self::connect(adapter, peripheral).await
+    }
+
+    pub async fn connect(adapter: Adapter, peripheral: Peripheral) -> std::io::Result<BleDevice> {
+        let (stream, tasks) = attach(&peripheral).await?;
+        Ok(BleDevice {
+            adapter,
+            peripheral,
+            stream,
+            tasks,
+            max_write: 20,
+            scan_timeout: Duration::from_secs(10),
+        })
+    }
+
+    // The largest Packet write the link takes, ATT_MTU minus 3. btleplug does not
+    // report the negotiated MTU, so this stays at the 20 bytes every link supports
+    // unless set.
+    pub fn set_max_write(&mut self, max_write: usize) {
+        self.max_write = max_write.max(1);
+    }
+
+    pub fn set_scan_timeout(&mut self, scan_timeout: Duration) {
+        self.scan_timeout = scan_timeout;
+    }
+
+    pub fn peripheral(&self) -> &Peripheral {
+        &self.peripheral
+    }
+
+    // Asks an application to restart into the bootloader. Returns false if the
+    // peripheral has no buttonless DFU characteristic.
+    pub async fn enter_bootloader(&mut self) -> std::io::Result<bool> {
+        let characteristics = self.peripheral.characteristics();
+        let buttonless = match characteristics.iter().find(|c| BUTTONLESS.contains(&c.uuid)) {
+            Some(buttonless) => buttonless.clone(),
+            None => return Ok(false),
+        };
+
+        self.peripheral.subscribe(&buttonless).await.map_err(ble_error)?;
+        let mut indications = self.peripheral.notifications().await.map_err(ble_error)?;
+        self.peripheral
+            .write(&buttonless, &[ENTER_BOOTLOADER], WriteType::WithResponse)
+            .await
+            .map_err(ble_error)?;
+
+        // The response echoes the opcode after 0x20, then the result; 0x01 is success.
+        let response = timeout(self.scan_timeout, async {
+            while let Some(indication) = indications.next().await {
+                if indication.uuid == buttonless.uuid {
+                    return Some(indication.value);
+                }
+            }
+            None
+        })
+        .await;
+        match response {
+            Ok(Some(value)) if value == [0x20, ENTER_BOOTLOADER, 0x01] => Ok(true),
+            Ok(Some(value)) => Err(std::io::Error::other(format!("buttonless DFU refused: {:02X?}", value))),
+            _ => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    fn stop_tasks(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for BleDevice {
+    fn drop(&mut self) {
+        self.stop_tasks();
+    }
+}
+
+async fn find_bootloader(adapter: &Adapter, scan_timeout: Duration) -> std::io::Result<Peripheral> {
+    adapter
+        .start_scan(ScanFilter {
+            services: vec![DFU_SERVICE],
+        })
+        .await
+        .map_err(ble_error)?;
+
+    // Applications with buttonless DFU advertise the same service, so only a
+    // peripheral that turns out to expose the Control Point will do.
+    let found = timeout(scan_timeout, async {
+        loop {
+            for peripheral in adapter.peripherals().await.map_err(ble_error)? {
+                let properties = peripheral.properties().await.map_err(ble_error)?;
+                if !properties.is_some_and(|properties| properties.services.contains(&DFU_SERVICE)) {
+                    continue;
+                }
+                if peripheral.connect().await.is_err() || peripheral.discover_services().await.is_err() {
+                    continue;
+                }
+                if peripheral.characteristics().iter().any(|c| c.uuid == CONTROL_POINT) {
+                    return Ok(peripheral);
+                }
+                let _ = peripheral.disconnect().await;
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await;
+
+    let _ = adapter.stop_scan().await;
+    found.map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?
+}
+
+async fn attach(peripheral: &Peripheral) -> std::io::Result<(DuplexStream, Vec<JoinHandle<()>>)> {
+    if !peripheral.is_connected().await.map_err(ble_error)? {
+        peripheral.connect().await.map_err(ble_error)?;
+    }
+    peripheral.discover_services().await.map_err(ble_error)?;
+
+    let (local, remote) = tokio::io::duplex(4096);
+    let characteristics = peripheral.characteristics();
+    let find = |uuid| characteristics.iter().find(|c| c.uuid == uuid).cloned();
+    let (control_point, packet) = match (find(CONTROL_POINT), find(PACKET)) {
+        (Some(control_point), Some(packet)) => (control_point, packet),
+        // An application: nothing to pump until it restarts into the bootloader.
+        _ => return Ok((local, vec![])),
+    };
+
+    peripheral.subscribe(&control_point).await.map_err(ble_error)?;
+    let notifications = peripheral.notifications().await.map_err(ble_error)?;
+    let (reader, writer) = tokio::io::split(remote);
+    let tasks = vec![
+        tokio::spawn(forward_writes(peripheral.clone(), control_point, packet, reader)),
+        tokio::spawn(forward_notifications(notifications, writer)),
+    ];
+    Ok((local, tasks))
+}
+
+async fn forward_writes(
+    peripheral: Peripheral,
+    control_point: btleplug::api::Characteristic,
+    packet: btleplug::api::Characteristic,
+    mut reader: ReadHalf<DuplexStream>,
+) {
+    let mut pending = vec![];
+    let mut buf = [0u8; 512];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(len) => pending.extend_from_slice(&buf[..len]),
+        }
+
+        while let Some(len) = tagged_len(&pending) {
+            let frame: Vec<u8> = pending.drain(..3 + len).collect();
+            let written = if is_packet(&frame) {
+                peripheral.write(&packet, &frame[3..], WriteType::WithoutResponse).await
+            } else {
+                peripheral.write(&control_point, &frame[3..], WriteType::WithResponse).await
+            };
+            if written.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn forward_notifications(mut notifications: Notifications, mut writer: WriteHalf<DuplexStream>) {
+    while let Some(notification) = notifications.next().await {
+        if notification.uuid != CONTROL_POINT {
+            continue;
+        }
+        let len = (notification.value.len() as u16).to_le_bytes();
+        if writer.write_all(&len).await.is_err() || writer.write_all(&notification.value).await.is_err() {
+            return;
+        }
+    }
+}
+
+impl AsyncRead for BleDevice {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BleDevice {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncNordicDevice for BleDevice {
+    type Codec = GattCodec;
+
+    // The bootloader starts the new application by itself, so only a request for the
+    // bootloader needs doing, and only while connected to an application.
+    async fn reset(&mut self, mode: ResetMode) {
+        if mode == ResetMode::Bootloader {
+            let _ = self.enter_bootloader().await;
+        }
+    }
+
+    async fn drain_input(&mut self) -> std::io::Result<()> {
+        let mut buf = [0u8; 256];
+        while let Some(read) = self.stream.read(&mut buf).now_or_never() {
+            if read? == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> std::io::Result<()> {
+        self.stop_tasks();
+        let _ = self.peripheral.disconnect().await;
+
+        self.peripheral = find_bootloader(&self.adapter, self.scan_timeout).await?;
+        let (stream, tasks) = attach(&self.peripheral).await?;
+        self.stream = stream;
+        self.tasks = tasks;
+        Ok(())
+    }
+
+    fn max_frame(&self) -> usize {
+        self.max_write
+    }
+}
@@ -0,0 +1,270 @@
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::codec::DfuCodec;
+use crate::updater::{LinkInfo, NordicDevice, ResetMode};
+
+// Codecs decode a byte at a time and hold no state between frames, so the read buffer
+// has to live on the transport. Wrapping an unbuffered serial port in this turns one
+// read per response byte into one read per buffer fill; writes already go out as one
+// call per frame and pass straight through. In benches/transfer.rs this cuts the reads
+// for a 150 KB image from about 3000 to about 300; on a real port each one is a syscall.
+//
+// Buffered bytes are discarded whenever the device is reset, drained or reconnected.
+pub struct BufferedDevice<D: Read> {
+    inner: BufReader<D>,
+}
+
+impl<D: Read> BufferedDevice<D> {
+    pub fn new(inner: D) -> Self {
+        BufferedDevice {
+            inner: BufReader::new(inner),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize, inner: D) -> Self {
+        BufferedDevice {
+            inner: BufReader::with_capacity(capacity, inner),
+        }
+    }
+
+    pub fn get_ref(&self) -> &D {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut D {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner.into_inner()
+    }
+
+    fn discard_buffer(&mut self) {
+        let buffered = self.inner.buffer().len();
+        self.inner.consume(buffered);
+    }
+}
+
+impl<D: Read> Read for BufferedDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<D: Read> BufRead for BufferedDevice<D> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+impl<D: Read + Write> Write for BufferedDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.get_mut().flush()
+    }
+}
+
+impl<D: NordicDevice> NordicDevice for BufferedDevice<D> {
+    type Codec = D::Codec;
+
+    fn reset(&mut self, mode: ResetMode) {
+        self.discard_buffer();
+        self.inner.get_mut().reset(mode);
+    }
+
+    fn drain_input(&mut self) -> std::io::Result<()> {
+        self.discard_buffer();
+        self.inner.get_mut().drain_input()
+    }
+
+    fn max_frame(&self) -> usize {
+        self.inner.get_ref().max_frame()
+    }
+
+    fn link_info(&self) -> LinkInfo {
+        self.inner.get_ref().link_info()
+    }
+
+    fn reconnect(&mut self) -> std::io::Result<()> {
+        self.discard_buffer();
+        self.inner.get_mut().reconnect()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.inner.get_mut().set_read_timeout(timeout)
+    }
+}
+
+// Wraps another codec and gathers everything it writes for a frame into one write
+// call, e.g. `type Codec = BufferedCodec<MyCodec>;` for a codec that sends a header and
+// its payload separately. The bytes on the wire are unchanged. The SLIP and USB codecs
+// already write each frame at once and gain nothing from it.
+//
+// Reads go straight to the inner codec: a codec is a type with nowhere to keep bytes
+// read past the end of a frame, so read buffering is BufferedDevice's job.
+pub struct BufferedCodec<C: DfuCodec>(PhantomData<C>);
+
+impl<C: DfuCodec> BufferedCodec<C>
+where
+    C::Error: From<std::io::Error>,
+{
+    fn buffered<T: Write>(
+        writer: &mut T,
+        write: impl FnOnce(&mut BufWriter<&mut T>) -> Result<usize, C::Error>,
+    ) -> Result<usize, C::Error> {
+        let mut buffered = BufWriter::new(writer);
+        let written = write(&mut buffered)?;
+        buffered.flush()?;
+        Ok(written)
+    }
+}
+
+impl<C: DfuCodec> DfuCodec for BufferedCodec<C>
+where
+    C::Error: From<std::io::Error>,
+{
+    type Error = C::Error;
+
+    fn decoded_read<T: Read>(reader: &mut T) -> Result<Vec<u8>, C::Error> {
+        C::decoded_read(reader)
+    }
+
+    fn resync<T: Read>(reader: &mut T) -> Result<(), C::Error> {
+        C::resync(reader)
+    }
+
+    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> Result<usize, C::Error> {
+        Self::buffered(writer, |writer| C::encoded_write(writer, buf))
+    }
+
+    fn encoded_write_chained<T: Write>(writer: &mut T, bufs: &[&[u8]]) -> Result<usize, C::Error> {
+        Self::buffered(writer, |writer| C::encoded_write_chained(writer, bufs))
+    }
+
+    fn encoded_write_into<T: Write>(
+        writer: &mut T,
+        bufs: &[&[u8]],
+        scratch: &mut Vec<u8>,
+    ) -> Result<usize, C::Error> {
+        Self::buffered(writer, |writer| C::encoded_write_into(writer, bufs, scratch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::DfuCodec;
+    use crate::slip::SlipCodec;
+
+    // Hands out its input in one read and counts the calls.
+    struct Port {
+        input: Vec<u8>,
+        reads: usize,
+    }
+
+    impl Read for Port {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            let len = buf.len().min(self.input.len());
+            buf[..len].copy_from_slice(&self.input[..len]);
+            self.input.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl NordicDevice for Port {
+        type Codec = SlipCodec;
+        fn reset(&mut self, _mode: ResetMode) {}
+    }
+
+    #[test]
+    fn frames_share_one_read() {
+        let input = vec![0x60, 0x09, 0x01, 0x7F, 0xC0, 0x60, 0x07, 0x01, 0x00, 0x01, 0xC0];
+        let mut device = BufferedDevice::new(Port { input, reads: 0 });
+
+        assert_eq!(SlipCodec::decoded_read(&mut device).unwrap(), vec![0x60, 0x09, 0x01, 0x7F]);
+        assert_eq!(SlipCodec::decoded_read(&mut device).unwrap(), vec![0x60, 0x07, 0x01, 0x00, 0x01]);
+        assert_eq!(device.get_ref().reads, 1);
+    }
+
+    // Writes a length byte and then the payload, as two calls.
+    struct SplitCodec;
+
+    impl DfuCodec for SplitCodec {
+        type Error = std::io::Error;
+
+        fn decoded_read<T: Read>(reader: &mut T) -> std::io::Result<Vec<u8>> {
+            let mut len = [0u8; 1];
+            reader.read_exact(&mut len)?;
+            let mut frame = vec![0u8; len[0] as usize];
+            reader.read_exact(&mut frame)?;
+            Ok(frame)
+        }
+
+        fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> std::io::Result<usize> {
+            writer.write_all(&[buf.len() as u8])?;
+            writer.write_all(buf)?;
+            Ok(buf.len() + 1)
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingWriter {
+        output: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn codec_writes_a_frame_at_once() {
+        let mut unbuffered = CountingWriter::default();
+        SplitCodec::encoded_write(&mut unbuffered, &[0x09, 0x01]).unwrap();
+        let mut buffered = CountingWriter::default();
+        BufferedCodec::<SplitCodec>::encoded_write_chained(&mut buffered, &[&[0x09], &[0x01]]).unwrap();
+
+        assert_eq!(buffered.output, unbuffered.output);
+        assert_eq!((unbuffered.writes, buffered.writes), (2, 1));
+        let mut input = &buffered.output[..];
+        assert_eq!(BufferedCodec::<SplitCodec>::decoded_read(&mut input).unwrap(), vec![0x09, 0x01]);
+    }
+
+    #[test]
+    fn drain_discards_buffered_bytes() {
+        let input = vec![0x60, 0x09, 0xC0, 0x60, 0x07, 0xC0];
+        let mut device = BufferedDevice::new(Port { input, reads: 0 });
+
+        SlipCodec::decoded_read(&mut device).unwrap();
+        device.drain_input().unwrap();
+        assert!(device.fill_buf().unwrap().is_empty());
+    }
+}
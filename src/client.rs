@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use crate::codec::DfuCodec;
+use crate::dfu::{check_response, DfuError, DfuRequest, DfuResponse, NoResponse, ObjectType};
+use crate::protocol::*;
+use crate::updater::{Error, NordicDevice};
+
+// One DFU request at a time, for tools that run their own sequence: diagnostics,
+// custom flows, or bootloaders that need something Updater doesn't do. Updater drives
+// a whole archive through one of these.
+pub struct DfuClient<'a, T: NordicDevice> {
+    comm: &'a mut T,
+    request_timeout: Duration,
+    execute_timeout: Duration,
+    read_timeout: Option<Duration>,
+    scratch: Vec<u8>,
+}
+
+impl<'a, T: NordicDevice> DfuClient<'a, T> {
+    pub fn new(comm: &'a mut T) -> Self {
+        DfuClient {
+            comm,
+            request_timeout: Duration::from_secs(5),
+            execute_timeout: Duration::from_secs(30),
+            read_timeout: None,
+            scratch: vec![],
+        }
+    }
+
+    // Executing an object can mean erasing and writing flash, so it gets its own,
+    // longer timeout than every other request.
+    pub fn set_timeouts(&mut self, request_timeout: Duration, execute_timeout: Duration) {
+        self.request_timeout = request_timeout;
+        self.execute_timeout = execute_timeout;
+    }
+
+    pub fn device(&mut self) -> &mut T {
+        self.comm
+    }
+
+    // The device may have reopened its port, losing whatever timeout was set on the old one.
+    pub(crate) fn forget_read_timeout(&mut self) {
+        self.read_timeout = None;
+    }
+
+    fn apply_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.read_timeout != Some(timeout) {
+            self.comm.set_read_timeout(timeout)?;
+            self.read_timeout = Some(timeout);
+        }
+        Ok(())
+    }
+
+    pub fn request<'de, Request: DfuRequest<'de>>(&mut self, request: Request) -> Result<Request::Response, Error> {
+        if Request::REQUEST_OPCODE == ObjectExecuteRequest::REQUEST_OPCODE {
+            self.apply_timeout(self.execute_timeout)?;
+        } else {
+            self.apply_timeout(self.request_timeout)?;
+        }
+        request.dfu_write::<T, T::Codec>(self.comm)?;
+        let response = Request::Response::dfu_read::<T, T::Codec, Request>(self.comm)?;
+        Ok(response)
+    }
+
+    /// Sends `[opcode, payload...]` as a single frame and returns the next decoded
+    /// frame verbatim, without checking its opcode or result code.
+    pub fn raw_exchange(&mut self, opcode: u8, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut request_data = vec![opcode];
+        request_data.extend_from_slice(payload);
+        T::Codec::encoded_write(self.comm, &request_data).map_err(Into::into)?;
+        T::Codec::decoded_read(self.comm).map_err(Into::into)
+    }
+
+    /// Like `raw_exchange`, but checks the response answers `opcode` and succeeded, and
+    /// returns just its payload. Meant for opcodes a customised bootloader adds.
+    pub fn send_raw(&mut self, opcode: u8, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.apply_timeout(self.request_timeout)?;
+        let mut response = self.raw_exchange(opcode, payload)?;
+        check_response(&mut response, opcode)?;
+        Ok(response.split_off(3))
+    }
+
+    pub fn ping(&mut self, id: u8) -> Result<(), Error> {
+        let PingResponse { id: echoed } = self.request(PingRequest { id })?;
+        if echoed != id {
+            return Err(Error::PingMismatch);
+        }
+        Ok(())
+    }
+
+    pub fn get_protocol_version(&mut self) -> Result<u8, Error> {
+        let ProtocolVersionResponse { version } = self.request(ProtocolVersionRequest)?;
+        Ok(version)
+    }
+
+    // Asks for a receipt every `target` object writes; 0 turns receipts off.
+    pub fn set_prn(&mut self, target: u16) -> Result<(), Error> {
+        self.request(SetReceiptNotifyRequest { target })?;
+        Ok(())
+    }
+
+    // None from bootloaders without the MTU request, such as most BLE ones.
+    pub fn get_mtu(&mut self) -> Result<Option<u16>, Error> {
+        match self.request(GetMtuRequest) {
+            Ok(GetMtuResponse { mtu }) => Ok(Some(mtu)),
+            Err(Error::DfuError(DfuError::OpcodeNotSupported)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn select(&mut self, object_type: ObjectType) -> Result<ObjectSelectResponse, Error> {
+        self.request(ObjectSelectRequest { object_type })
+    }
+
+    pub fn create(&mut self, object_type: ObjectType, object_size: u32) -> Result<(), Error> {
+        self.request(ObjectCreateRequest {
+            object_type,
+            object_size,
+        })?;
+        Ok(())
+    }
+
+    // Sends a chunk of the current object without waiting for anything back. Once
+    // a receipt is due, as set by `set_prn`, read it with `read_receipt`.
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        ObjectWriteRequest::<NoResponse>::new(chunk).dfu_write_into::<T, T::Codec>(self.comm, &mut self.scratch)
+    }
+
+    pub fn read_receipt(&mut self) -> Result<ObjectWriteResponse, Error> {
+        ObjectWriteResponse::dfu_read::<T, T::Codec, ObjectWriteRequest>(self.comm)
+    }
+
+    pub fn get_crc(&mut self) -> Result<GetCrcResponse, Error> {
+        self.request(GetCrcRequest)
+    }
+
+    pub fn execute(&mut self) -> Result<(), Error> {
+        self.request(ObjectExecuteRequest)?;
+        Ok(())
+    }
+
+    pub fn abort(&mut self) -> Result<(), Error> {
+        self.request(AbortRequest)?;
+        Ok(())
+    }
+
+    /// Returns `None` from bootloaders built without the hardware version request.
+    pub fn get_hardware_version(&mut self) -> Result<Option<GetHardwareVersionResponse>, Error> {
+        match self.request(GetHardwareVersionRequest) {
+            Ok(hardware) => Ok(Some(hardware)),
+            Err(Error::DfuError(DfuError::OpcodeNotSupported)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn get_firmware_version(&mut self, image: u8) -> Result<GetFirmwareVersionResponse, Error> {
+        self.request(GetFirmwareVersionRequest { image })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crc::crc32;
+
+    use super::*;
+    use crate::mock::MockDevice;
+
+    #[test]
+    fn drives_an_object_by_hand() {
+        let data: Vec<u8> = (0..200u8).collect();
+        let mut device = MockDevice::new();
+        let mut client = DfuClient::new(&mut device);
+        client.ping(0x42).unwrap();
+        client.set_prn(2).unwrap();
+        assert_eq!(client.get_mtu().unwrap(), Some(515));
+
+        let ObjectSelectResponse { offset, .. } = client.select(ObjectType::Command).unwrap();
+        assert_eq!(offset, 0);
+        client.create(ObjectType::Command, data.len() as u32).unwrap();
+        client.write(&data[..100]).unwrap();
+        client.write(&data[100..]).unwrap();
+        let ObjectWriteResponse { offset, crc } = client.read_receipt().unwrap();
+        assert_eq!((offset, crc), (200, crc32::checksum_ieee(&data)));
+        assert_eq!(client.get_crc().unwrap().offset, 200);
+        client.execute().unwrap();
+
+        assert_eq!(device.executed(ObjectType::Command), &data[..]);
+    }
+
+    #[test]
+    fn reports_missing_mtu_request_as_none() {
+        let mut device = MockDevice::new();
+        device.set_unsupported(0x07);
+        let mut client = DfuClient::new(&mut device);
+        assert_eq!(client.get_mtu().unwrap(), None);
+        assert!(matches!(client.get_hardware_version(), Ok(None)));
+    }
+}
@@ -1,6 +1,56 @@
 use std::io::{Read, Write};
 
+use crate::updater;
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite};
+
 pub trait DfuCodec {
-    fn decoded_read<T: Read>(reader: &mut T) -> std::io::Result<Vec<u8>>;
-    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> std::io::Result<usize>;
-}
\ No newline at end of file
+    // Codecs that only wrap a byte stream use io::Error; others, such as a GATT codec,
+    // can report their own failures as long as they convert into the updater's error.
+    type Error: Into<updater::Error>;
+
+    fn decoded_read<T: Read>(reader: &mut T) -> Result<Vec<u8>, Self::Error>;
+    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> Result<usize, Self::Error>;
+
+    fn encoded_write_chained<T: Write>(writer: &mut T, bufs: &[&[u8]]) -> Result<usize, Self::Error> {
+        Self::encoded_write(writer, &bufs.concat())
+    }
+
+    // Like encoded_write_chained, but codecs may encode into `scratch`, a buffer the
+    // caller keeps across frames, instead of allocating one per frame.
+    fn encoded_write_into<T: Write>(
+        writer: &mut T,
+        bufs: &[&[u8]],
+        scratch: &mut Vec<u8>,
+    ) -> Result<usize, Self::Error> {
+        let _ = scratch;
+        Self::encoded_write_chained(writer, bufs)
+    }
+
+    // Called after a frame failed to decode partway through: discards input up to the
+    // next frame boundary so the following decoded_read starts clean. Codecs whose
+    // frames cannot break off midway need not override it.
+    fn resync<T: Read>(reader: &mut T) -> Result<(), Self::Error> {
+        let _ = reader;
+        Ok(())
+    }
+}
+
+// The async counterpart of DfuCodec, used by AsyncUpdater.
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncDfuCodec {
+    async fn decoded_read<T: AsyncRead + Unpin>(reader: &mut T) -> std::io::Result<Vec<u8>>;
+    async fn encoded_write<T: AsyncWrite + Unpin>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize>;
+
+    // See DfuCodec::encoded_write_into.
+    async fn encoded_write_into<T: AsyncWrite + Unpin>(
+        writer: &mut T,
+        bufs: &[&[u8]],
+        scratch: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        let _ = scratch;
+        Self::encoded_write(writer, bufs).await
+    }
+}
@@ -1,12 +1,20 @@
+use core::fmt;
+#[cfg(feature = "std")]
 use std::io::{Read, Write};
 
+#[cfg(feature = "std")]
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_repr::*;
 
+#[cfg(feature = "std")]
 use crate::codec::DfuCodec;
+#[cfg(feature = "std")]
 use crate::updater::Error;
 
-#[derive(Debug)]
+// NRF_DFU_OP_RESPONSE
+pub const OP_RESPONSE: u8 = 0x60;
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum DfuError {
     InvalidOpcode,
     OpcodeNotSupported,
@@ -16,17 +24,83 @@ pub enum DfuError {
     UnsupportedType,
     OperationNotPermitted,
     OperationFailed,
-    ExtendedError,
+    ExtendedError(ExtErrorCode),
     UnknownError,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Copy, Clone)]
+// NRF_DFU_EXT_ERROR_*, sent as an extra byte after an extended error result code.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExtErrorCode {
+    NoError,
+    InvalidErrorCode,
+    WrongCommandFormat,
+    UnknownCommand,
+    InitCommandInvalid,
+    FwVersionFailure,
+    HwVersionFailure,
+    SdVersionFailure,
+    SignatureMissing,
+    WrongHashType,
+    HashFailed,
+    WrongSignatureType,
+    VerificationFailed,
+    InsufficientSpace,
+    Unknown,
+}
+
+impl From<u8> for ExtErrorCode {
+    fn from(code: u8) -> ExtErrorCode {
+        match code {
+            0x00 => ExtErrorCode::NoError,
+            0x01 => ExtErrorCode::InvalidErrorCode,
+            0x02 => ExtErrorCode::WrongCommandFormat,
+            0x03 => ExtErrorCode::UnknownCommand,
+            0x04 => ExtErrorCode::InitCommandInvalid,
+            0x05 => ExtErrorCode::FwVersionFailure,
+            0x06 => ExtErrorCode::HwVersionFailure,
+            0x07 => ExtErrorCode::SdVersionFailure,
+            0x08 => ExtErrorCode::SignatureMissing,
+            0x09 => ExtErrorCode::WrongHashType,
+            0x0A => ExtErrorCode::HashFailed,
+            0x0B => ExtErrorCode::WrongSignatureType,
+            0x0C => ExtErrorCode::VerificationFailed,
+            0x0D => ExtErrorCode::InsufficientSpace,
+            _ => ExtErrorCode::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for ExtErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ExtErrorCode::NoError => "no extended error",
+            ExtErrorCode::InvalidErrorCode => "invalid error code",
+            ExtErrorCode::WrongCommandFormat => "wrong command format",
+            ExtErrorCode::UnknownCommand => "unknown command",
+            ExtErrorCode::InitCommandInvalid => "init command invalid",
+            ExtErrorCode::FwVersionFailure => "firmware version check failed",
+            ExtErrorCode::HwVersionFailure => "hardware version check failed",
+            ExtErrorCode::SdVersionFailure => "SoftDevice version check failed",
+            ExtErrorCode::SignatureMissing => "signature missing",
+            ExtErrorCode::WrongHashType => "wrong hash type",
+            ExtErrorCode::HashFailed => "hash check failed",
+            ExtErrorCode::WrongSignatureType => "wrong signature type",
+            ExtErrorCode::VerificationFailed => "signature verification failed",
+            ExtErrorCode::InsufficientSpace => "insufficient space",
+            ExtErrorCode::Unknown => "unknown extended error",
+        };
+        f.write_str(message)
+    }
+}
+
+#[derive(Serialize_repr, Deserialize_repr, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum ObjectType {
     Command = 0x01,
     Data = 0x02,
 }
 
+// NRF_DFU_RES_CODE_*; 0x01 is success, and 0x06 and 0x09 are unassigned.
 impl From<u8> for DfuError {
     fn from(err_code: u8) -> DfuError {
         match err_code {
@@ -35,77 +109,318 @@ impl From<u8> for DfuError {
             0x03 => DfuError::InvalidParameter,
             0x04 => DfuError::InsufficientResources,
             0x05 => DfuError::InvalidObject,
-            0x06 => DfuError::UnsupportedType,
-            0x07 => DfuError::OperationNotPermitted,
-            0x08 => DfuError::OperationFailed,
-            0x09 => DfuError::ExtendedError,
+            0x07 => DfuError::UnsupportedType,
+            0x08 => DfuError::OperationNotPermitted,
+            0x0A => DfuError::OperationFailed,
+            0x0B => DfuError::ExtendedError(ExtErrorCode::Unknown),
             _ => DfuError::UnknownError,
         }
     }
 }
 
+impl fmt::Display for DfuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            DfuError::InvalidOpcode => "invalid opcode",
+            DfuError::OpcodeNotSupported => "opcode not supported",
+            DfuError::InvalidParameter => "invalid parameter",
+            DfuError::InsufficientResources => "insufficient resources",
+            DfuError::InvalidObject => "invalid object",
+            DfuError::UnsupportedType => "unsupported object type",
+            DfuError::OperationNotPermitted => "operation not permitted",
+            DfuError::OperationFailed => "operation failed",
+            DfuError::ExtendedError(code) => return write!(f, "extended error: {}", code),
+            DfuError::UnknownError => "unknown error",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DfuError {}
+
+#[cfg(feature = "std")]
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
-        Error::IOError(err)
+        match err.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::IOError(err),
+        }
     }
 }
 
+#[cfg(feature = "std")]
 impl From<DfuError> for Error {
     fn from(err: DfuError) -> Error {
         Error::DfuError(err)
     }
 }
 
+#[cfg(feature = "std")]
 pub trait DfuSerialize {
-    fn serialize(self) -> Vec<u8>;
+    fn serialize(&self) -> Result<Vec<u8>, Error>;
 }
 
+#[cfg(feature = "std")]
 impl<T: Serialize> DfuSerialize for T {
-    fn serialize(self) -> Vec<u8> {
-        bincode::serialize(&self).unwrap()
+    fn serialize(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(self).map_err(Error::Encode)
     }
 }
 
+#[cfg(feature = "std")]
 pub trait DfuRequest<'de>: Sized + DfuSerialize {
     const REQUEST_OPCODE: u8;
     const RESPONSE_OPCODE: u8 = Self::REQUEST_OPCODE;
     type Response: DfuResponse<'de>;
 
     fn dfu_write<Writer: Write, Codec: DfuCodec>(self, writer: &mut Writer) -> Result<(), Error> {
-        let mut request_data = vec![Self::REQUEST_OPCODE];
-        request_data.extend_from_slice(&self.serialize());
-        Codec::encoded_write(writer, &request_data)?;
+        Codec::encoded_write_chained(writer, &[&[Self::REQUEST_OPCODE], &self.serialize()?]).map_err(Into::into)?;
         Ok(())
     }
 }
 
+#[cfg(feature = "std")]
 pub trait DfuResponse<'de>: Sized + DeserializeOwned {
+    // False for requests the device never answers, so no frame should be read.
+    const EXPECTED: bool = true;
+
     fn dfu_read<Reader: Read, Codec: DfuCodec, Request: DfuRequest<'de>>(reader: &mut Reader) -> Result<Self, Error> {
-        let response = Codec::decoded_read(reader)?;
+        let response = Codec::decoded_read(reader).map_err(Into::into)?;
+        Self::from_frame::<Request>(response)
+    }
 
-        assert!(response.len() >= 2);
+    fn from_frame<Request: DfuRequest<'de>>(mut response: Vec<u8>) -> Result<Self, Error> {
+        check_response(&mut response, Request::RESPONSE_OPCODE)?;
+        bincode::deserialize(&response[3..]).map_err(Error::Decode)
+    }
+}
 
-        if response[0] != Request::RESPONSE_OPCODE {
-            return Err(Error::DfuError(DfuError::InvalidOpcode));
-        }
-        if response[1] != 1 {
-            Err(Error::DfuError(DfuError::from(response[1])))
-        } else {
-            Ok(bincode::deserialize(&response[2..]).unwrap())
-        }
+// Checks that `response` answers `opcode` and reports success; its payload starts at byte 3.
+// Bridges read with PlainSlipCodec may leave off the leading 0x60, sending just
+// `[opcode, result, payload...]`; it's put back so the payload is in the same place.
+#[cfg(feature = "std")]
+pub(crate) fn check_response(response: &mut Vec<u8>, opcode: u8) -> Result<(), Error> {
+    if response.first() == Some(&opcode) && opcode != OP_RESPONSE {
+        response.insert(0, OP_RESPONSE);
+    }
+    if response.len() < 3 {
+        return Err(Error::UnexpectedResponse(response.to_vec()));
+    }
+    check_header(response, opcode).map_err(Error::DfuError)
+}
+
+// The part of check_response that doesn't need the updater's Error, for the no_std
+// client. `response` must be at least the three header bytes long.
+#[cfg(any(feature = "std", feature = "no_std"))]
+pub(crate) fn check_header(response: &[u8], opcode: u8) -> Result<(), DfuError> {
+    if response[0] != OP_RESPONSE || response[1] != opcode {
+        return Err(DfuError::InvalidOpcode);
     }
+    if response[2] != 1 {
+        let err = match DfuError::from(response[2]) {
+            DfuError::ExtendedError(_) => {
+                DfuError::ExtendedError(response.get(3).map_or(ExtErrorCode::Unknown, |&code| code.into()))
+            }
+            err => err,
+        };
+        return Err(err);
+    }
+    Ok(())
 }
 
+#[cfg(feature = "std")]
 #[derive(Deserialize, Debug)]
 pub struct NoResponse;
 
+#[cfg(feature = "std")]
 impl<'de> DfuResponse<'de> for NoResponse {
+    const EXPECTED: bool = false;
+
     fn dfu_read<Reader: Read, Codec: DfuCodec, Request: DfuRequest<'de>>(_reader: &mut Reader) -> Result<Self, Error> {
         Ok(NoResponse)
     }
+
+    fn from_frame<Request: DfuRequest<'de>>(_response: Vec<u8>) -> Result<Self, Error> {
+        Ok(NoResponse)
+    }
 }
 
+#[cfg(feature = "std")]
 #[derive(Deserialize, Debug)]
 pub struct NoDataResponse;
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for NoDataResponse {}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::protocol::*;
+    use crate::slip::SlipCodec;
+
+    fn read<'de, Request: DfuRequest<'de>>(frame: &[u8]) -> Result<Request::Response, Error> {
+        let mut reader = Cursor::new([frame, &[0xC0]].concat());
+        Request::Response::dfu_read::<_, SlipCodec, Request>(&mut reader)
+    }
+
+    #[test]
+    fn reads_framed_responses() {
+        let ProtocolVersionResponse { version } = read::<ProtocolVersionRequest>(&[0x60, 0x00, 0x01, 0x01]).unwrap();
+        assert_eq!(version, 1);
+
+        let GetMtuResponse { mtu } = read::<GetMtuRequest>(&[0x60, 0x07, 0x01, 0x83, 0x00]).unwrap();
+        assert_eq!(mtu, 131);
+
+        let GetCrcResponse { offset, crc } =
+            read::<GetCrcRequest>(&[0x60, 0x03, 0x01, 0x10, 0, 0, 0, 0x78, 0x56, 0x34, 0x12]).unwrap();
+        assert_eq!(offset, 16);
+        assert_eq!(crc, 0x12345678);
+
+        let ObjectWriteResponse { offset, .. } =
+            read::<ObjectWriteRequest>(&[0x60, 0x03, 0x01, 0x20, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(offset, 32);
+    }
+
+    #[test]
+    fn rejects_mismatched_opcodes() {
+        assert!(matches!(
+            read::<GetMtuRequest>(&[0x60, 0x03, 0x01, 0x83, 0x00]),
+            Err(Error::DfuError(DfuError::InvalidOpcode))
+        ));
+        assert!(matches!(
+            read::<ObjectWriteRequest>(&[0x60, 0x08, 0x01, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(Error::DfuError(DfuError::InvalidOpcode))
+        ));
+    }
+
+    #[test]
+    fn maps_result_codes() {
+        assert!(matches!(
+            read::<PingRequest>(&[0x60, 0x09, 0x02]),
+            Err(Error::DfuError(DfuError::OpcodeNotSupported))
+        ));
+    }
+
+    #[test]
+    fn maps_documented_result_codes() {
+        let codes = [
+            (0x00, DfuError::InvalidOpcode),
+            (0x02, DfuError::OpcodeNotSupported),
+            (0x03, DfuError::InvalidParameter),
+            (0x04, DfuError::InsufficientResources),
+            (0x05, DfuError::InvalidObject),
+            (0x06, DfuError::UnknownError),
+            (0x07, DfuError::UnsupportedType),
+            (0x08, DfuError::OperationNotPermitted),
+            (0x09, DfuError::UnknownError),
+            (0x0A, DfuError::OperationFailed),
+            (0x0B, DfuError::ExtendedError(ExtErrorCode::Unknown)),
+            (0xFF, DfuError::UnknownError),
+        ];
+        for (code, expected) in codes {
+            assert_eq!(DfuError::from(code), expected, "result code {:#04x}", code);
+        }
+    }
+
+    #[test]
+    fn decodes_extended_error_code() {
+        assert!(matches!(
+            read::<ObjectExecuteRequest>(&[0x60, 0x04, 0x0B, 0x0A]),
+            Err(Error::DfuError(DfuError::ExtendedError(ExtErrorCode::HashFailed)))
+        ));
+        assert!(matches!(
+            read::<ObjectExecuteRequest>(&[0x60, 0x04, 0x0B]),
+            Err(Error::DfuError(DfuError::ExtendedError(ExtErrorCode::Unknown)))
+        ));
+    }
+
+    #[test]
+    fn reports_short_frames() {
+        match read::<PingRequest>(&[0x60, 0x09]) {
+            Err(Error::UnexpectedResponse(frame)) => assert_eq!(frame, vec![0x60, 0x09]),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_truncated_payload() {
+        assert!(matches!(
+            read::<GetCrcRequest>(&[0x60, 0x03, 0x01, 0x10, 0x00]),
+            Err(Error::Decode(_))
+        ));
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("not encodable"))
+        }
+    }
+
+    impl DfuRequest<'_> for Unserializable {
+        const REQUEST_OPCODE: u8 = 0x7F;
+        type Response = NoResponse;
+    }
+
+    #[test]
+    fn reports_serialization_failure() {
+        let mut written = vec![];
+        assert!(matches!(
+            Unserializable.dfu_write::<_, SlipCodec>(&mut written),
+            Err(Error::Encode(_))
+        ));
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn serializing_leaves_request_reusable() {
+        let request = ObjectCreateRequest {
+            object_type: ObjectType::Data,
+            object_size: 0x1000,
+        };
+        assert_eq!(DfuSerialize::serialize(&request).unwrap(), vec![0x02, 0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(DfuSerialize::serialize(&request).unwrap(), vec![0x02, 0x00, 0x10, 0x00, 0x00]);
+    }
+
+    struct NotificationsDisabled;
+
+    impl From<NotificationsDisabled> for Error {
+        fn from(_: NotificationsDisabled) -> Error {
+            Error::NoBootloaderResponse
+        }
+    }
+
+    struct GattCodec;
+
+    impl DfuCodec for GattCodec {
+        type Error = NotificationsDisabled;
+
+        fn decoded_read<T: Read>(_reader: &mut T) -> Result<Vec<u8>, NotificationsDisabled> {
+            Err(NotificationsDisabled)
+        }
+
+        fn encoded_write<T: Write>(_writer: &mut T, buf: &[u8]) -> Result<usize, NotificationsDisabled> {
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn codec_errors_convert_into_updater_errors() {
+        let mut reader = Cursor::new(vec![]);
+        assert!(matches!(
+            GetMtuResponse::dfu_read::<_, GattCodec, GetMtuRequest>(&mut reader),
+            Err(Error::NoBootloaderResponse)
+        ));
+    }
+
+    #[test]
+    fn displays_readable_errors() {
+        assert_eq!(DfuError::OperationNotPermitted.to_string(), "operation not permitted");
+        let boxed: Box<dyn std::error::Error> = Box::new(DfuError::InsufficientResources);
+        assert_eq!(boxed.to_string(), "insufficient resources");
+    }
+}
@@ -0,0 +1,326 @@
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+use embedded_io::{Read, Write};
+
+use crate::dfu::{check_header, DfuError, ObjectType};
+use crate::protocol::*;
+use crate::slip::{encode_chained_into, SlipDecoder, SlipError, SLIP_END};
+
+#[derive(Debug)]
+pub enum Error<E> {
+    IOError(E),
+    // The port returned no more bytes partway through a response.
+    UnexpectedEof,
+    Slip(SlipError),
+    DfuError(DfuError),
+    PingMismatch,
+    UnexpectedResponse(Vec<u8>),
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(err) => write!(f, "I/O error: {:?}", err),
+            Error::UnexpectedEof => write!(f, "port closed partway through a response"),
+            Error::Slip(err) => write!(f, "{}", err),
+            Error::DfuError(err) => write!(f, "device returned an error: {}", err),
+            Error::PingMismatch => write!(f, "ping response did not match the request"),
+            Error::UnexpectedResponse(frame) => write!(f, "unexpected response frame {:02X?}", frame),
+        }
+    }
+}
+
+// DfuClient's requests, SLIP framed, over an embedded-io port: for a microcontroller
+// updating another over UART without std. embedded-io has no timeouts, so a port that
+// can lose bytes should give up on a read itself and return an error.
+pub struct EmbeddedClient<T: Read + Write> {
+    port: T,
+    scratch: Vec<u8>,
+}
+
+fn u32_at(payload: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(payload[index..index + 4].try_into().unwrap())
+}
+
+impl<T: Read + Write> EmbeddedClient<T> {
+    pub fn new(port: T) -> Self {
+        EmbeddedClient { port, scratch: Vec::new() }
+    }
+
+    pub fn port(&mut self) -> &mut T {
+        &mut self.port
+    }
+
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    fn send(&mut self, bufs: &[&[u8]]) -> Result<(), Error<T::Error>> {
+        encode_chained_into(&mut self.scratch, bufs);
+        self.port.write_all(&self.scratch).map_err(Error::IOError)?;
+        self.port.flush().map_err(Error::IOError)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error<T::Error>> {
+        let mut byte = [0u8];
+        match self.port.read(&mut byte).map_err(Error::IOError)? {
+            0 => Err(Error::UnexpectedEof),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    // A byte at a time, like SlipCodec, so nothing past the frame's END is consumed.
+    fn read_frame(&mut self) -> Result<Vec<u8>, Error<T::Error>> {
+        let mut decoder = SlipDecoder::with_sentinel();
+        loop {
+            match decoder.push_byte(self.read_byte()?) {
+                Ok(Some(frame)) => return Ok(frame),
+                Ok(None) => {}
+                Err(err) => {
+                    if err.is_mid_frame() {
+                        while self.read_byte()? != SLIP_END {}
+                    }
+                    return Err(Error::Slip(err));
+                }
+            }
+        }
+    }
+
+    // Returns the payload of the response to `opcode`, which must hold at least `len` bytes.
+    fn read_response(&mut self, opcode: u8, len: usize) -> Result<Vec<u8>, Error<T::Error>> {
+        let mut response = self.read_frame()?;
+        if response.len() < 3 {
+            return Err(Error::UnexpectedResponse(response));
+        }
+        check_header(&response, opcode).map_err(Error::DfuError)?;
+        if response.len() < 3 + len {
+            return Err(Error::UnexpectedResponse(response));
+        }
+        Ok(response.split_off(3))
+    }
+
+    fn request(&mut self, opcode: u8, payload: &[u8], len: usize) -> Result<Vec<u8>, Error<T::Error>> {
+        self.send(&[&[opcode], payload])?;
+        self.read_response(opcode, len)
+    }
+
+    pub fn ping(&mut self, id: u8) -> Result<(), Error<T::Error>> {
+        if self.request(0x09, &[id], 1)?[0] != id {
+            return Err(Error::PingMismatch);
+        }
+        Ok(())
+    }
+
+    pub fn get_protocol_version(&mut self) -> Result<u8, Error<T::Error>> {
+        Ok(self.request(0x00, &[], 1)?[0])
+    }
+
+    pub fn set_prn(&mut self, target: u16) -> Result<(), Error<T::Error>> {
+        self.request(0x02, &target.to_le_bytes(), 0)?;
+        Ok(())
+    }
+
+    pub fn get_mtu(&mut self) -> Result<Option<u16>, Error<T::Error>> {
+        match self.request(0x07, &[], 2) {
+            Ok(payload) => Ok(Some(u16::from_le_bytes([payload[0], payload[1]]))),
+            Err(Error::DfuError(DfuError::OpcodeNotSupported)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn select(&mut self, object_type: ObjectType) -> Result<ObjectSelectResponse, Error<T::Error>> {
+        let payload = self.request(0x06, &[object_type as u8], 12)?;
+        Ok(ObjectSelectResponse {
+            max_size: u32_at(&payload, 0),
+            offset: u32_at(&payload, 4),
+            crc: u32_at(&payload, 8),
+        })
+    }
+
+    pub fn create(&mut self, object_type: ObjectType, object_size: u32) -> Result<(), Error<T::Error>> {
+        let size = object_size.to_le_bytes();
+        self.request(0x01, &[object_type as u8, size[0], size[1], size[2], size[3]], 0)?;
+        Ok(())
+    }
+
+    // See DfuClient::write.
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), Error<T::Error>> {
+        self.send(&[&[0x08], chunk])
+    }
+
+    pub fn read_receipt(&mut self) -> Result<ObjectWriteResponse, Error<T::Error>> {
+        let payload = self.read_response(0x03, 8)?;
+        Ok(ObjectWriteResponse {
+            offset: u32_at(&payload, 0),
+            crc: u32_at(&payload, 4),
+        })
+    }
+
+    pub fn get_crc(&mut self) -> Result<GetCrcResponse, Error<T::Error>> {
+        let payload = self.request(0x03, &[], 8)?;
+        Ok(GetCrcResponse {
+            offset: u32_at(&payload, 0),
+            crc: u32_at(&payload, 4),
+        })
+    }
+
+    pub fn execute(&mut self) -> Result<(), Error<T::Error>> {
+        self.request(0x04, &[], 0)?;
+        Ok(())
+    }
+
+    // Abort has no response.
+    pub fn abort(&mut self) -> Result<(), Error<T::Error>> {
+        self.send(&[&[0x0C]])
+    }
+
+    pub fn get_hardware_version(&mut self) -> Result<Option<GetHardwareVersionResponse>, Error<T::Error>> {
+        let payload = match self.request(0x0A, &[], 20) {
+            Ok(payload) => payload,
+            Err(Error::DfuError(DfuError::OpcodeNotSupported)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        Ok(Some(GetHardwareVersionResponse {
+            part: u32_at(&payload, 0),
+            variant: u32_at(&payload, 4),
+            rom_size: u32_at(&payload, 8),
+            ram_size: u32_at(&payload, 12),
+            rom_page_size: u32_at(&payload, 16),
+        }))
+    }
+
+    pub fn get_firmware_version(&mut self, image: u8) -> Result<GetFirmwareVersionResponse, Error<T::Error>> {
+        let payload = self.request(0x0B, &[image], 13)?;
+        Ok(GetFirmwareVersionResponse {
+            firmware_type: payload[0],
+            version: u32_at(&payload, 1),
+            address: u32_at(&payload, 5),
+            length: u32_at(&payload, 9),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+    use alloc::vec;
+
+    use crc::crc32;
+    use embedded_io::{ErrorKind, ErrorType};
+
+    use super::*;
+    use crate::slip::encode;
+
+    // Answers from a script of responses, SLIP framed, and keeps what was written. Only
+    // needs alloc, so these tests also run without std.
+    struct Port {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Port {
+        fn new(responses: &[&[u8]]) -> Port {
+            Port {
+                input: responses.iter().flat_map(|response| encode(response)).collect(),
+                output: vec![],
+            }
+        }
+    }
+
+    impl ErrorType for Port {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Port {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            let len = buf.len().min(self.input.len());
+            for (slot, byte) in buf.iter_mut().zip(self.input.drain(..len)) {
+                *slot = byte;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), ErrorKind> {
+            Ok(())
+        }
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn transfers_an_object() {
+        let data: Vec<u8> = (0..200u8).collect();
+        let crc = crc32::checksum_ieee(&data);
+        let mut client = EmbeddedClient::new(Port::new(&[
+            &[0x60, 0x09, 0x01, 0x42],
+            &[0x60, 0x00, 0x01, 0x01],
+            &[0x60, 0x02, 0x01],
+            &[0x60, 0x07, 0x01, 0x03, 0x02],
+            &[&[0x60, 0x06, 0x01][..], &words(&[4096, 0, 0])].concat(),
+            &[0x60, 0x01, 0x01],
+            &[&[0x60, 0x03, 0x01][..], &words(&[200, crc])].concat(),
+            &[&[0x60, 0x03, 0x01][..], &words(&[200, crc])].concat(),
+            &[0x60, 0x04, 0x01],
+        ]));
+        client.ping(0x42).unwrap();
+        assert_eq!(client.get_protocol_version().unwrap(), 1);
+        client.set_prn(2).unwrap();
+        assert_eq!(client.get_mtu().unwrap(), Some(515));
+
+        assert_eq!(client.select(ObjectType::Command).unwrap().max_size, 4096);
+        client.create(ObjectType::Command, data.len() as u32).unwrap();
+        client.write(&data[..100]).unwrap();
+        client.write(&data[100..]).unwrap();
+        let ObjectWriteResponse { offset, crc: written } = client.read_receipt().unwrap();
+        assert_eq!((offset, written), (200, crc));
+        assert_eq!(client.get_crc().unwrap().offset, 200);
+        client.execute().unwrap();
+
+        let requests: Vec<Vec<u8>> = vec![
+            vec![0x09, 0x42],
+            vec![0x00],
+            vec![0x02, 0x02, 0x00],
+            vec![0x07],
+            vec![0x06, 0x01],
+            vec![0x01, 0x01, 200, 0, 0, 0],
+            [&[0x08][..], &data[..100]].concat(),
+            [&[0x08][..], &data[100..]].concat(),
+            vec![0x03],
+            vec![0x04],
+        ];
+        let expected: Vec<u8> = requests.iter().flat_map(|request| encode(request)).collect();
+        assert_eq!(client.into_inner().output, expected);
+    }
+
+    #[test]
+    fn maps_result_codes() {
+        let mut client = EmbeddedClient::new(Port::new(&[
+            &[0x60, 0x07, 0x02],
+            &[0x60, 0x0A, 0x02],
+            &[0x60, 0x01, 0x04],
+        ]));
+        assert_eq!(client.get_mtu().unwrap(), None);
+        assert!(matches!(client.get_hardware_version(), Ok(None)));
+        assert!(matches!(
+            client.create(ObjectType::Data, 8192),
+            Err(Error::DfuError(DfuError::InsufficientResources))
+        ));
+    }
+
+    #[test]
+    fn port_running_dry_is_an_error() {
+        let mut client = EmbeddedClient::new(Port::new(&[]));
+        assert!(matches!(client.ping(0x01), Err(Error::UnexpectedEof)));
+    }
+}
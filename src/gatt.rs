@@ -0,0 +1,285 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[cfg(feature = "async")]
+use crate::codec::AsyncDfuCodec;
+use crate::codec::DfuCodec;
+use crate::slip::DEFAULT_MAX_FRAME_LEN;
+use crate::updater::{LinkInfo, NordicDevice, ResetMode};
+
+const OBJECT_WRITE_OPCODE: u8 = 0x08;
+const CONTROL_POINT: u8 = 0x00;
+const PACKET: u8 = 0x01;
+
+// Frames GattCodec writes: the characteristic tag, the payload length as a
+// little-endian u16, then the payload itself.
+pub(crate) fn is_packet(frame: &[u8]) -> bool {
+    frame[0] == PACKET
+}
+
+// Returns the payload length once `frame` holds a whole tagged frame.
+pub(crate) fn tagged_len(frame: &[u8]) -> Option<usize> {
+    let len = u16::from_le_bytes([*frame.get(1)?, *frame.get(2)?]) as usize;
+    Some(len).filter(|len| frame.len() >= 3 + len)
+}
+
+fn tag_frame(bufs: &[&[u8]], scratch: &mut Vec<u8>) -> std::io::Result<usize> {
+    scratch.clear();
+    scratch.extend_from_slice(&[CONTROL_POINT, 0, 0]);
+    for buf in bufs {
+        scratch.extend_from_slice(buf);
+    }
+    if scratch.get(3) == Some(&OBJECT_WRITE_OPCODE) {
+        scratch.remove(3);
+        scratch[0] = PACKET;
+    }
+
+    let len = scratch.len() - 3;
+    if len > DEFAULT_MAX_FRAME_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "GATT write too long"));
+    }
+    scratch[1..3].copy_from_slice(&(len as u16).to_le_bytes());
+    Ok(len)
+}
+
+// The two characteristics of the Nordic DFU service, as exposed by whichever BLE stack
+// the caller uses.
+pub trait GattLink {
+    // Write with response to the DFU Control Point characteristic.
+    fn write_control_point(&mut self, data: &[u8]) -> std::io::Result<()>;
+    // Write without response to the DFU Packet characteristic.
+    fn write_packet(&mut self, data: &[u8]) -> std::io::Result<()>;
+    // Blocks until the next Control Point notification arrives.
+    fn next_notification(&mut self) -> std::io::Result<Vec<u8>>;
+    fn reset(&mut self, mode: ResetMode);
+
+    // ATT_MTU minus the 3-byte write header; 20 on links that never negotiated.
+    fn max_write(&self) -> usize {
+        20
+    }
+
+    fn link_info(&self) -> LinkInfo {
+        LinkInfo::Unknown
+    }
+
+    fn reconnect(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Tags each request with the characteristic it belongs on: ObjectWrite data goes to
+// the Packet characteristic without its opcode, everything else to the Control Point.
+// Only meaningful on a GattDevice, which splits the tagged frames back out.
+pub struct GattCodec;
+
+impl DfuCodec for GattCodec {
+    type Error = std::io::Error;
+
+    fn decoded_read<T: Read>(reader: &mut T) -> std::io::Result<Vec<u8>> {
+        let mut len = [0u8; 2];
+        reader.read_exact(&mut len)?;
+        let mut notification = vec![0u8; u16::from_le_bytes(len) as usize];
+        reader.read_exact(&mut notification)?;
+        Ok(notification)
+    }
+
+    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> std::io::Result<usize> {
+        Self::encoded_write_chained(writer, &[buf])
+    }
+
+    fn encoded_write_chained<T: Write>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize> {
+        <Self as DfuCodec>::encoded_write_into(writer, bufs, &mut vec![])
+    }
+
+    fn encoded_write_into<T: Write>(writer: &mut T, bufs: &[&[u8]], scratch: &mut Vec<u8>) -> std::io::Result<usize> {
+        let len = tag_frame(bufs, scratch)?;
+        writer.write_all(scratch)?;
+        writer.flush()?;
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncDfuCodec for GattCodec {
+    async fn decoded_read<T: AsyncRead + Unpin>(reader: &mut T) -> std::io::Result<Vec<u8>> {
+        let mut notification = vec![0u8; reader.read_u16_le().await? as usize];
+        reader.read_exact(&mut notification).await?;
+        Ok(notification)
+    }
+
+    async fn encoded_write<T: AsyncWrite + Unpin>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize> {
+        <Self as AsyncDfuCodec>::encoded_write_into(writer, bufs, &mut vec![]).await
+    }
+
+    async fn encoded_write_into<T: AsyncWrite + Unpin>(
+        writer: &mut T,
+        bufs: &[&[u8]],
+        scratch: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        let len = tag_frame(bufs, scratch)?;
+        writer.write_all(scratch).await?;
+        writer.flush().await?;
+        Ok(len)
+    }
+}
+
+// Presents a GattLink as the byte stream NordicDevice expects, routing GattCodec's
+// tagged frames to their characteristic and queueing notifications for reading.
+pub struct GattDevice<L: GattLink> {
+    link: L,
+    outgoing: Vec<u8>,
+    incoming: VecDeque<u8>,
+}
+
+impl<L: GattLink> GattDevice<L> {
+    pub fn new(link: L) -> Self {
+        GattDevice {
+            link,
+            outgoing: vec![],
+            incoming: VecDeque::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &L {
+        &self.link
+    }
+
+    pub fn get_mut(&mut self) -> &mut L {
+        &mut self.link
+    }
+
+    pub fn into_inner(self) -> L {
+        self.link
+    }
+}
+
+impl<L: GattLink> Read for GattDevice<L> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.incoming.is_empty() {
+            let notification = self.link.next_notification()?;
+            self.incoming.extend((notification.len() as u16).to_le_bytes());
+            self.incoming.extend(notification);
+        }
+
+        let len = buf.len().min(self.incoming.len());
+        for (byte, queued) in buf.iter_mut().zip(self.incoming.drain(..len)) {
+            *byte = queued;
+        }
+        Ok(len)
+    }
+}
+
+impl<L: GattLink> Write for GattDevice<L> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+
+        while let Some(len) = tagged_len(&self.outgoing) {
+            let frame: Vec<u8> = self.outgoing.drain(..3 + len).collect();
+            if is_packet(&frame) {
+                self.link.write_packet(&frame[3..])?;
+            } else {
+                self.link.write_control_point(&frame[3..])?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<L: GattLink> NordicDevice for GattDevice<L> {
+    type Codec = GattCodec;
+
+    fn reset(&mut self, mode: ResetMode) {
+        self.outgoing.clear();
+        self.incoming.clear();
+        self.link.reset(mode);
+    }
+
+    fn drain_input(&mut self) -> std::io::Result<()> {
+        self.incoming.clear();
+        Ok(())
+    }
+
+    fn max_frame(&self) -> usize {
+        self.link.max_write()
+    }
+
+    fn link_info(&self) -> LinkInfo {
+        self.link.link_info()
+    }
+
+    fn reconnect(&mut self) -> std::io::Result<()> {
+        self.outgoing.clear();
+        self.incoming.clear();
+        self.link.reconnect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::updater::Updater;
+
+    #[derive(Default)]
+    struct FakeLink {
+        control_point: Vec<Vec<u8>>,
+        packets: Vec<Vec<u8>>,
+        notifications: VecDeque<Vec<u8>>,
+    }
+
+    impl GattLink for FakeLink {
+        fn write_control_point(&mut self, data: &[u8]) -> std::io::Result<()> {
+            self.control_point.push(data.to_vec());
+            Ok(())
+        }
+
+        fn write_packet(&mut self, data: &[u8]) -> std::io::Result<()> {
+            self.packets.push(data.to_vec());
+            Ok(())
+        }
+
+        fn next_notification(&mut self) -> std::io::Result<Vec<u8>> {
+            self.notifications
+                .pop_front()
+                .ok_or_else(|| std::io::ErrorKind::TimedOut.into())
+        }
+
+        fn reset(&mut self, _mode: ResetMode) {}
+    }
+
+    fn notification(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        [&[0x60, opcode, 0x01], payload].concat()
+    }
+
+    #[test]
+    fn routes_object_data_to_packet_characteristic() {
+        let init = [0x12u8; 24];
+        let crc = crc::crc32::checksum_ieee(&init);
+        let mut link = FakeLink::default();
+        link.notifications.extend([
+            notification(0x09, &[0x7F]),
+            notification(0x00, &[0x01]),
+            notification(0x02, &[]),
+            notification(0x07, &0x0100u16.to_le_bytes()),
+            notification(0x06, &[[0x00, 0x02, 0, 0], [0; 4], [0; 4]].concat()),
+            notification(0x01, &[]),
+            notification(0x03, &[(init.len() as u32).to_le_bytes(), crc.to_le_bytes()].concat()),
+            notification(0x04, &[]),
+        ]);
+
+        let mut device = GattDevice::new(link);
+        Updater::new(&mut device, false).try_init(&init).unwrap();
+
+        let link = device.into_inner();
+        assert_eq!(link.packets, vec![init[..20].to_vec(), init[20..].to_vec()]);
+        assert_eq!(link.control_point[0], vec![0x09, 0x7F]);
+        assert!(link.control_point.iter().all(|request| request[0] != OBJECT_WRITE_OPCODE));
+        assert!(link.notifications.is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+// Decoder for the init packet (.dat) described by nRF5 SDK's dfu-cc.proto:
+//
+//   Packet        { Command command = 1; SignedCommand signed_command = 2; }
+//   SignedCommand { Command command = 1; SignatureType signature_type = 2; bytes signature = 3; }
+//   Command       { OpCode op_code = 1; InitCommand init = 2; }
+//   InitCommand   { uint32 fw_version = 1; uint32 hw_version = 2; repeated uint32 sd_req = 3;
+//                   FwType type = 4; uint32 sd_size = 5; uint32 bl_size = 6; uint32 app_size = 7;
+//                   Hash hash = 8; bool is_debug = 9; }
+//   Hash          { HashType hash_type = 1; bytes hash = 2; }
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum InitPacketError {
+    Truncated,
+    UnsupportedWireType(u8),
+    MissingInitCommand,
+}
+
+impl fmt::Display for InitPacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InitPacketError::Truncated => write!(f, "truncated protobuf"),
+            InitPacketError::UnsupportedWireType(wire_type) => write!(f, "unsupported wire type {}", wire_type),
+            InitPacketError::MissingInitCommand => write!(f, "no init command found"),
+        }
+    }
+}
+
+impl std::error::Error for InitPacketError {}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InitPacket {
+    pub fw_version: Option<u32>,
+    pub hw_version: Option<u32>,
+    pub sd_req: Vec<u32>,
+    pub fw_type: Option<u32>,
+    pub sd_size: Option<u32>,
+    pub bl_size: Option<u32>,
+    pub app_size: Option<u32>,
+    pub hash_type: Option<u32>,
+    pub hash: Option<Vec<u8>>,
+    pub is_debug: bool,
+    pub signed: bool,
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, InitPacketError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.data.split_first().ok_or(InitPacketError::Truncated)?;
+            self.data = rest;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(InitPacketError::Truncated)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], InitPacketError> {
+        if self.data.len() < len {
+            return Err(InitPacketError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn field(&mut self) -> Result<Option<(u64, Value<'a>)>, InitPacketError> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let value = match key & 0x07 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            wire_type => return Err(InitPacketError::UnsupportedWireType(wire_type as u8)),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+fn fields(data: &[u8]) -> Reader<'_> {
+    Reader { data }
+}
+
+impl InitPacket {
+    pub fn parse(data: &[u8]) -> Result<InitPacket, InitPacketError> {
+        let mut packet = fields(data);
+        while let Some((number, value)) = packet.field()? {
+            match (number, value) {
+                (1, Value::Bytes(command)) => return Self::parse_command(command, false),
+                (2, Value::Bytes(signed_command)) => {
+                    let mut signed_command = fields(signed_command);
+                    while let Some((number, value)) = signed_command.field()? {
+                        if let (1, Value::Bytes(command)) = (number, value) {
+                            return Self::parse_command(command, true);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(InitPacketError::MissingInitCommand)
+    }
+
+    fn parse_command(data: &[u8], signed: bool) -> Result<InitPacket, InitPacketError> {
+        let mut command = fields(data);
+        while let Some((number, value)) = command.field()? {
+            if let (2, Value::Bytes(init)) = (number, value) {
+                let mut packet = Self::parse_init(init)?;
+                packet.signed = signed;
+                return Ok(packet);
+            }
+        }
+        Err(InitPacketError::MissingInitCommand)
+    }
+
+    fn parse_init(data: &[u8]) -> Result<InitPacket, InitPacketError> {
+        let mut packet = InitPacket::default();
+        let mut init = fields(data);
+        while let Some((number, value)) = init.field()? {
+            match (number, value) {
+                (1, Value::Varint(value)) => packet.fw_version = Some(value as u32),
+                (2, Value::Varint(value)) => packet.hw_version = Some(value as u32),
+                (3, Value::Varint(value)) => packet.sd_req.push(value as u32),
+                (3, Value::Bytes(packed)) => {
+                    let mut packed = fields(packed);
+                    while !packed.data.is_empty() {
+                        packet.sd_req.push(packed.varint()? as u32);
+                    }
+                }
+                (4, Value::Varint(value)) => packet.fw_type = Some(value as u32),
+                (5, Value::Varint(value)) => packet.sd_size = Some(value as u32),
+                (6, Value::Varint(value)) => packet.bl_size = Some(value as u32),
+                (7, Value::Varint(value)) => packet.app_size = Some(value as u32),
+                (8, Value::Bytes(hash)) => {
+                    let mut hash = fields(hash);
+                    while let Some((number, value)) = hash.field()? {
+                        match (number, value) {
+                            (1, Value::Varint(value)) => packet.hash_type = Some(value as u32),
+                            (2, Value::Bytes(value)) => packet.hash = Some(value.to_vec()),
+                            _ => {}
+                        }
+                    }
+                }
+                (9, Value::Varint(value)) => packet.is_debug = value != 0,
+                _ => {}
+            }
+        }
+        Ok(packet)
+    }
+}
+
+// The parsed init packets of an archive's images, in `FirmwareArchive::images` order,
+// with `None` for a .dat that isn't a protobuf init packet. Lets a caller that has
+// already inspected them, or corrected a value, hand them to the updater as they are.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InitPacketSet {
+    pub packets: Vec<Option<InitPacket>>,
+}
+
+impl InitPacketSet {
+    pub fn get(&self, index: usize) -> Option<&InitPacket> {
+        self.packets.get(index)?.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    fn uint(number: u64, value: u64) -> Vec<u8> {
+        [varint(number << 3), varint(value)].concat()
+    }
+
+    fn bytes(number: u64, value: &[u8]) -> Vec<u8> {
+        [varint(number << 3 | 2), varint(value.len() as u64), value.to_vec()].concat()
+    }
+
+    fn init_command() -> Vec<u8> {
+        [
+            uint(1, 7),
+            uint(2, 52),
+            bytes(3, &[varint(0xFFFE), varint(0x0100)].concat()),
+            uint(4, 0),
+            uint(7, 45_000),
+            bytes(8, &[uint(1, 3), bytes(2, &[0xAB; 32])].concat()),
+            uint(9, 0),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn parses_signed_packet() {
+        let command = [uint(1, 1), bytes(2, &init_command())].concat();
+        let signed_command = [bytes(1, &command), uint(2, 0), bytes(3, &[0x55; 64])].concat();
+        let packet = InitPacket::parse(&bytes(2, &signed_command)).unwrap();
+
+        assert!(packet.signed);
+        assert_eq!(packet.fw_version, Some(7));
+        assert_eq!(packet.hw_version, Some(52));
+        assert_eq!(packet.sd_req, vec![0xFFFE, 0x0100]);
+        assert_eq!(packet.fw_type, Some(0));
+        assert_eq!(packet.app_size, Some(45_000));
+        assert_eq!(packet.sd_size, None);
+        assert_eq!(packet.hash_type, Some(3));
+        assert_eq!(packet.hash, Some(vec![0xAB; 32]));
+        assert!(!packet.is_debug);
+    }
+
+    #[test]
+    fn parses_unsigned_packet() {
+        let command = [uint(1, 1), bytes(2, &init_command())].concat();
+        let packet = InitPacket::parse(&bytes(1, &command)).unwrap();
+
+        assert!(!packet.signed);
+        assert_eq!(packet.fw_version, Some(7));
+    }
+
+    #[test]
+    fn rejects_truncated_packet() {
+        let command = [uint(1, 1), bytes(2, &init_command())].concat();
+        let packet = bytes(1, &command);
+
+        assert!(matches!(
+            InitPacket::parse(&packet[..packet.len() - 4]),
+            Err(InitPacketError::Truncated)
+        ));
+    }
+}
@@ -1,9 +1,44 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod archive;
 pub mod dfu;
+#[cfg(feature = "std")]
+pub mod init_packet;
+#[cfg(feature = "std")]
+pub mod plan;
 pub mod protocol;
+#[cfg(feature = "std")]
 pub mod codec;
 pub mod slip;
+#[cfg(feature = "std")]
+pub mod buffered;
+#[cfg(feature = "std")]
+pub mod recording;
+#[cfg(feature = "std")]
+pub mod usb;
+#[cfg(feature = "std")]
+pub mod gatt;
+#[cfg(feature = "std")]
+pub mod tcp;
+#[cfg(feature = "std")]
+pub mod reset;
+#[cfg(all(feature = "std", feature = "serialport"))]
+pub mod serial;
+#[cfg(feature = "std")]
 pub mod updater;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "no_std")]
+pub mod embedded;
+#[cfg(all(feature = "std", any(test, feature = "testing")))]
+pub mod mock;
+#[cfg(feature = "async")]
+pub mod async_updater;
+#[cfg(feature = "ble")]
+pub mod ble;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,342 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use crc::crc32;
+
+use crate::codec::DfuCodec;
+use crate::dfu::ObjectType;
+use crate::slip::{SlipCodec, SlipDecoder};
+use crate::updater::{NordicDevice, ResetMode};
+
+#[derive(Default)]
+struct Object {
+    data: Vec<u8>,
+    crc: u32,
+    executed: usize,
+    executed_crc: u32,
+}
+
+impl Object {
+    fn rewind(&mut self) {
+        self.data.truncate(self.executed);
+        self.crc = self.executed_crc;
+    }
+}
+
+// A NordicDevice that answers like a SLIP bootloader, keeping the received command
+// and data objects in memory so tests can drive the whole transfer, resumes and
+// retries included, without hardware. Opcodes it does not simulate, and any passed to
+// `set_unsupported`, are answered with OpcodeNotSupported.
+pub struct MockDevice {
+    decoder: SlipDecoder,
+    output: VecDeque<u8>,
+    prn: u16,
+    writes: u16,
+    mtu: u16,
+    max_size: u32,
+    objects: [Object; 2],
+    current: usize,
+    crc_errors: usize,
+    unsupported: Vec<u8>,
+    firmware_images: Option<Vec<(u8, u32)>>,
+    resets: Vec<ResetMode>,
+}
+
+impl Default for MockDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockDevice {
+    pub fn new() -> Self {
+        MockDevice {
+            decoder: SlipDecoder::new(),
+            output: VecDeque::new(),
+            prn: 0,
+            writes: 0,
+            mtu: 515,
+            max_size: 4096,
+            objects: Default::default(),
+            current: 0,
+            crc_errors: 0,
+            unsupported: vec![],
+            firmware_images: None,
+            resets: vec![],
+        }
+    }
+
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.mtu = mtu;
+    }
+
+    pub fn set_max_object_size(&mut self, max_size: u32) {
+        self.max_size = max_size;
+    }
+
+    // The next `count` CRCs reported, in GetCrc responses or receipts, are wrong.
+    pub fn inject_crc_errors(&mut self, count: usize) {
+        self.crc_errors = count;
+    }
+
+    pub fn set_unsupported(&mut self, opcode: u8) {
+        self.unsupported.push(opcode);
+    }
+
+    // Answers GetFirmwareVersion with these (firmware type, version) pairs, image 0
+    // first. Until set, the request is unsupported, as on bootloaders before SDK 15.
+    pub fn set_firmware_images(&mut self, images: &[(u8, u32)]) {
+        self.firmware_images = Some(images.to_vec());
+    }
+
+    // Stores `data` as if received by an earlier update that was cut off; as on a real
+    // bootloader, only whole objects of it count as executed.
+    pub fn preload(&mut self, object_type: ObjectType, data: &[u8]) {
+        let object = &mut self.objects[object_type as usize - 1];
+        object.data = data.to_vec();
+        object.crc = crc32::checksum_ieee(data);
+        object.executed = data.len() - data.len() % self.max_size as usize;
+        object.executed_crc = crc32::checksum_ieee(&data[..object.executed]);
+    }
+
+    // Everything of `object_type` that has been executed.
+    pub fn executed(&self, object_type: ObjectType) -> &[u8] {
+        let object = &self.objects[object_type as usize - 1];
+        &object.data[..object.executed]
+    }
+
+    pub fn resets(&self) -> &[ResetMode] {
+        &self.resets
+    }
+
+    fn respond(&mut self, opcode: u8, result: u8, payload: &[u8]) {
+        let mut frame = vec![];
+        SlipCodec::encoded_write_chained(&mut frame, &[&[0x60, opcode, result], payload]).unwrap();
+        self.output.extend(frame);
+    }
+
+    fn crc_payload(&self) -> Vec<u8> {
+        let object = &self.objects[self.current];
+        [(object.data.len() as u32).to_le_bytes(), object.crc.to_le_bytes()].concat()
+    }
+
+    // The offset and CRC for a GetCrc response or receipt, corrupted if one is due.
+    fn crc_report(&mut self) -> Vec<u8> {
+        let mut payload = self.crc_payload();
+        if self.crc_errors > 0 {
+            self.crc_errors -= 1;
+            payload[4] ^= 0xFF;
+        }
+        payload
+    }
+
+    fn select(&mut self, object_type: u8) -> bool {
+        if object_type == ObjectType::Command as u8 || object_type == ObjectType::Data as u8 {
+            self.current = object_type as usize - 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn handle(&mut self, request: &[u8]) {
+        let (opcode, payload) = match request.split_first() {
+            Some((&opcode, payload)) => (opcode, payload),
+            None => return,
+        };
+        if self.unsupported.contains(&opcode) {
+            self.respond(opcode, 0x02, &[]);
+            return;
+        }
+        // Receipts count the writes since the last request of any other kind.
+        if opcode != 0x08 {
+            self.writes = 0;
+        }
+
+        match opcode {
+            0x00 => self.respond(opcode, 0x01, &[0x01]),
+            // Creating an object drops whatever was received since the last execute.
+            0x01 if payload.len() == 5 && self.select(payload[0]) => {
+                let size = u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]]);
+                if size > self.max_size {
+                    self.respond(opcode, 0x04, &[]);
+                    return;
+                }
+                // As on the real bootloader, a new init packet discards all progress,
+                // while a new data object only drops what wasn't executed.
+                if self.current == 0 {
+                    self.objects = Default::default();
+                } else {
+                    self.objects[1].rewind();
+                }
+                self.respond(opcode, 0x01, &[]);
+            }
+            0x02 if payload.len() == 2 => {
+                self.prn = u16::from_le_bytes([payload[0], payload[1]]);
+                self.respond(opcode, 0x01, &[]);
+            }
+            0x03 => {
+                let crc = self.crc_report();
+                self.respond(opcode, 0x01, &crc);
+            }
+            0x04 => {
+                let object = &mut self.objects[self.current];
+                object.executed = object.data.len();
+                object.executed_crc = object.crc;
+                self.respond(opcode, 0x01, &[]);
+            }
+            0x06 if payload.len() == 1 && self.select(payload[0]) => {
+                let payload = [self.max_size.to_le_bytes().to_vec(), self.crc_payload()].concat();
+                self.respond(opcode, 0x01, &payload);
+            }
+            0x07 => self.respond(opcode, 0x01, &self.mtu.to_le_bytes()),
+            0x08 => {
+                let object = &mut self.objects[self.current];
+                object.data.extend_from_slice(payload);
+                object.crc = crc32::update(object.crc, &crc32::IEEE_TABLE, payload);
+                self.writes += 1;
+                if self.prn > 0 && self.writes == self.prn {
+                    self.writes = 0;
+                    let crc = self.crc_report();
+                    self.respond(0x03, 0x01, &crc);
+                }
+            }
+            0x09 => self.respond(opcode, 0x01, payload),
+            0x0B if payload.len() == 1 && self.firmware_images.is_some() => {
+                match self.firmware_images.as_ref().and_then(|images| images.get(payload[0] as usize)) {
+                    Some(&(firmware_type, version)) => {
+                        let payload = [&[firmware_type][..], &version.to_le_bytes(), &[0; 8]].concat();
+                        self.respond(opcode, 0x01, &payload);
+                    }
+                    None => self.respond(opcode, 0x03, &[]),
+                }
+            }
+            // Abort has no response.
+            0x0C => {
+                for object in &mut self.objects {
+                    object.rewind();
+                }
+            }
+            0x01 | 0x02 | 0x06 => self.respond(opcode, 0x03, &[]),
+            _ => self.respond(opcode, 0x02, &[]),
+        }
+    }
+}
+
+impl Read for MockDevice {
+    // Like a serial port with a read timeout, reading with no response pending times out.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.output.is_empty() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        let len = buf.len().min(self.output.len());
+        for (byte, queued) in buf.iter_mut().zip(self.output.drain(..len)) {
+            *byte = queued;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MockDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.decoder.push(buf)?;
+        while let Some(request) = self.decoder.next_frame() {
+            self.handle(&request);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl NordicDevice for MockDevice {
+    type Codec = SlipCodec;
+
+    fn reset(&mut self, mode: ResetMode) {
+        self.resets.push(mode);
+        self.output.clear();
+        self.prn = 0;
+        self.writes = 0;
+    }
+
+    fn drain_input(&mut self) -> std::io::Result<()> {
+        self.output.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::archive::{FirmwareArchive, FirmwareData, ManifestInfo};
+    use crate::updater::{Error, UpdaterBuilder};
+
+    fn archive(bin: &[u8], init: &[u8]) -> FirmwareArchive {
+        FirmwareArchive {
+            bootloader: None,
+            softdevice_bootloader: None,
+            applications: vec![FirmwareData::new(bin.to_vec(), init.to_vec())],
+            manifest: ManifestInfo::default(),
+            raw_manifest: String::new(),
+        }
+    }
+
+    fn builder() -> UpdaterBuilder {
+        UpdaterBuilder::new().reset_delays(Duration::ZERO, Duration::ZERO)
+    }
+
+    #[test]
+    fn resumes_interrupted_update() {
+        let bin: Vec<u8> = (0..10000u32).map(|i| (i * 7) as u8).collect();
+        let init = [0x12u8; 100];
+        let mut device = MockDevice::new();
+        device.preload(ObjectType::Command, &init);
+        device.preload(ObjectType::Data, &bin[..5000]);
+
+        let mut updater = builder().prn(4).auto_boot(true).build(&mut device);
+        let stats = updater.update_with_stats(&archive(&bin, &init)).unwrap();
+        assert_eq!(stats.data_bytes, 5000);
+        drop(updater);
+        assert_eq!(device.executed(ObjectType::Command), &init[..]);
+        assert_eq!(device.executed(ObjectType::Data), &bin[..]);
+        assert_eq!(device.resets(), &[ResetMode::Application]);
+    }
+
+    #[test]
+    fn restarts_when_partial_data_is_from_another_image() {
+        let bin: Vec<u8> = (0..6000u32).map(|i| (i * 7) as u8).collect();
+        let init = [0x12u8; 100];
+        let mut device = MockDevice::new();
+        device.preload(ObjectType::Command, &init);
+        device.preload(ObjectType::Data, &[0xFF; 5000]);
+
+        let stats = builder().build(&mut device).update_with_stats(&archive(&bin, &init)).unwrap();
+        assert_eq!(stats.data_bytes, bin.len());
+        assert_eq!(device.executed(ObjectType::Data), &bin[..]);
+    }
+
+    #[test]
+    fn retries_after_crc_error() {
+        let bin = vec![0xA5u8; 6000];
+        let init = [0x12u8; 100];
+        let mut device = MockDevice::new();
+        device.inject_crc_errors(2);
+        device.set_unsupported(0x07);
+
+        let mut updater = builder().max_retries(2).build(&mut device);
+        let stats = updater.update_with_stats(&archive(&bin, &init)).unwrap();
+        assert_eq!(stats.retries, 2);
+        drop(updater);
+        assert_eq!(device.executed(ObjectType::Data), &bin[..]);
+
+        let mut device = MockDevice::new();
+        device.inject_crc_errors(1);
+        let result = builder().max_retries(0).build(&mut device).update(&archive(&bin, &init));
+        assert!(matches!(result, Err(Error::CrcMismatch)));
+        assert!(device.executed(ObjectType::Data).is_empty());
+    }
+}
@@ -0,0 +1,25 @@
+use serde::Serialize;
+
+use crate::archive::ImageKind;
+use crate::updater::LinkInfo;
+
+#[derive(Serialize, Debug)]
+pub struct TransferPlan {
+    pub link: LinkInfo,
+    pub protocol_version: Option<u8>,
+    pub mtu: Option<u16>,
+    pub chunk_size: usize,
+    pub prn: u16,
+    pub images: Vec<ImagePlan>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImagePlan {
+    pub kind: ImageKind,
+    pub init_size: usize,
+    pub init_crc: u32,
+    pub command_objects: usize,
+    pub firmware_size: usize,
+    pub firmware_crc: u32,
+    pub data_objects: usize,
+}
@@ -1,11 +1,22 @@
+use alloc::string::String;
+#[cfg(feature = "std")]
+use std::io::Write;
+
 use serde::{Deserialize, Serialize};
 
-use crate::dfu::{DfuRequest, DfuResponse, DfuSerialize, NoDataResponse, NoResponse, ObjectType};
+#[cfg(feature = "std")]
+use crate::codec::DfuCodec;
+use crate::dfu::ObjectType;
+#[cfg(feature = "std")]
+use crate::dfu::{DfuRequest, DfuResponse, DfuSerialize, NoDataResponse, NoResponse};
+#[cfg(feature = "std")]
+use crate::updater::Error;
 
 // NRF_DFU_OP_PROTOCOL_VERSION
 #[derive(Serialize)]
 pub struct ProtocolVersionRequest;
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for ProtocolVersionRequest {
     const REQUEST_OPCODE: u8 = 0x00;
     type Response = ProtocolVersionResponse;
@@ -16,6 +27,7 @@ pub struct ProtocolVersionResponse {
     pub version: u8,
 }
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for ProtocolVersionResponse {}
 
 // NRF_DFU_OP_OBJECT_CREATE
@@ -25,6 +37,7 @@ pub struct ObjectCreateRequest {
     pub object_size: u32,
 }
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for ObjectCreateRequest {
     const REQUEST_OPCODE: u8 = 0x01;
     type Response = NoDataResponse;
@@ -36,6 +49,7 @@ pub struct SetReceiptNotifyRequest {
     pub target: u16,
 }
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for SetReceiptNotifyRequest {
     const REQUEST_OPCODE: u8 = 0x02;
     type Response = NoDataResponse;
@@ -45,6 +59,7 @@ impl DfuRequest<'_> for SetReceiptNotifyRequest {
 #[derive(Serialize)]
 pub struct GetCrcRequest;
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for GetCrcRequest {
     const REQUEST_OPCODE: u8 = 0x03;
     type Response = GetCrcResponse;
@@ -56,12 +71,14 @@ pub struct GetCrcResponse {
     pub crc: u32,
 }
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for GetCrcResponse {}
 
 // NRF_DFU_OP_OBJECT_EXECUTE
 #[derive(Serialize)]
 pub struct ObjectExecuteRequest;
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for ObjectExecuteRequest {
     const REQUEST_OPCODE: u8 = 0x04;
     type Response = NoDataResponse;
@@ -73,6 +90,7 @@ pub struct ObjectSelectRequest {
     pub object_type: ObjectType,
 }
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for ObjectSelectRequest {
     const REQUEST_OPCODE: u8 = 0x06;
     type Response = ObjectSelectResponse;
@@ -85,12 +103,14 @@ pub struct ObjectSelectResponse {
     pub crc: u32,
 }
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for ObjectSelectResponse {}
 
 // NRF_DFU_OP_MTU_GET
 #[derive(Serialize)]
 pub struct GetMtuRequest;
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for GetMtuRequest {
     const REQUEST_OPCODE: u8 = 0x07;
     type Response = GetMtuResponse;
@@ -101,38 +121,70 @@ pub struct GetMtuResponse {
     pub mtu: u16,
 }
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for GetMtuResponse {}
 
 // NRF_DFU_OP_OBJECT_WRITE
+#[cfg(feature = "std")]
+const OBJECT_WRITE_OPCODE: u8 = 0x08;
+
+#[cfg(feature = "std")]
 pub struct ObjectWriteRequest<'de, T: DfuResponse<'de> = ObjectWriteResponse> {
-    pub data: Vec<u8>,
-    phantom: std::marker::PhantomData<&'de T>,
+    pub data: &'de [u8],
+    phantom: std::marker::PhantomData<T>,
 }
 
+#[cfg(feature = "std")]
 impl<'de, T: DfuResponse<'de>> DfuSerialize for ObjectWriteRequest<'de, T> {
-    fn serialize(self) -> Vec<u8> {
-        self.data
+    fn serialize(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.data.to_vec())
     }
 }
 
+#[cfg(feature = "std")]
 impl<'de> DfuRequest<'de> for ObjectWriteRequest<'de, ObjectWriteResponse> {
-    const REQUEST_OPCODE: u8 = 0x08;
+    const REQUEST_OPCODE: u8 = OBJECT_WRITE_OPCODE;
     const RESPONSE_OPCODE: u8 = 0x03;
     type Response = ObjectWriteResponse;
+
+    fn dfu_write<Writer: Write, Codec: DfuCodec>(self, writer: &mut Writer) -> Result<(), Error> {
+        self.write_chunk::<Writer, Codec>(Self::REQUEST_OPCODE, writer)
+    }
 }
 
+#[cfg(feature = "std")]
 impl<'de> DfuRequest<'de> for ObjectWriteRequest<'de, NoResponse> {
-    const REQUEST_OPCODE: u8 = 0x08;
+    const REQUEST_OPCODE: u8 = OBJECT_WRITE_OPCODE;
     type Response = NoResponse;
+
+    fn dfu_write<Writer: Write, Codec: DfuCodec>(self, writer: &mut Writer) -> Result<(), Error> {
+        self.write_chunk::<Writer, Codec>(Self::REQUEST_OPCODE, writer)
+    }
 }
 
+#[cfg(feature = "std")]
 impl<'de, T: DfuResponse<'de>> ObjectWriteRequest<'de, T> {
-    pub fn new(data: &[u8]) -> ObjectWriteRequest<'de, T> {
+    pub fn new(data: &'de [u8]) -> ObjectWriteRequest<'de, T> {
         ObjectWriteRequest {
-            data: Vec::from(data),
+            data,
             phantom: std::marker::PhantomData,
         }
     }
+
+    // Like dfu_write, but encodes into `scratch`, which the caller reuses across chunks.
+    pub fn dfu_write_into<Writer: Write, Codec: DfuCodec>(
+        self,
+        writer: &mut Writer,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        Codec::encoded_write_into(writer, &[&[OBJECT_WRITE_OPCODE], self.data], scratch).map_err(Into::into)?;
+        Ok(())
+    }
+
+    fn write_chunk<Writer: Write, Codec: DfuCodec>(self, opcode: u8, writer: &mut Writer) -> Result<(), Error> {
+        Codec::encoded_write_chained(writer, &[&[opcode], self.data]).map_err(Into::into)?;
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -141,6 +193,7 @@ pub struct ObjectWriteResponse {
     pub crc: u32,
 }
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for ObjectWriteResponse {}
 
 // NRF_DFU_OP_PING
@@ -149,6 +202,7 @@ pub struct PingRequest {
     pub id: u8,
 }
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for PingRequest {
     const REQUEST_OPCODE: u8 = 0x09;
     type Response = PingResponse;
@@ -159,12 +213,14 @@ pub struct PingResponse {
     pub id: u8,
 }
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for PingResponse {}
 
 // NRF_DFU_OP_HARDWARE_VERSION
 #[derive(Serialize)]
 pub struct GetHardwareVersionRequest;
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for GetHardwareVersionRequest {
     const REQUEST_OPCODE: u8 = 0x0A;
     type Response = GetHardwareVersionResponse;
@@ -179,19 +235,62 @@ pub struct GetHardwareVersionResponse {
     pub rom_page_size: u32,
 }
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for GetHardwareVersionResponse {}
 
+impl GetHardwareVersionResponse {
+    // FICR INFO.PART holds the part number as hex digits, e.g. 0x52840.
+    pub fn part_name(&self) -> Option<&'static str> {
+        match self.part {
+            0x52805 => Some("nRF52805"),
+            0x52810 => Some("nRF52810"),
+            0x52811 => Some("nRF52811"),
+            0x52820 => Some("nRF52820"),
+            0x52832 => Some("nRF52832"),
+            0x52833 => Some("nRF52833"),
+            0x52840 => Some("nRF52840"),
+            _ => None,
+        }
+    }
+
+    // FICR INFO.VARIANT packs four ASCII characters, most significant first.
+    pub fn variant_string(&self) -> String {
+        self.variant.to_be_bytes().iter().map(|&b| b as char).collect()
+    }
+}
+
 // NRF_DFU_OP_FIRMWARE_VERSION
 #[derive(Serialize)]
 pub struct GetFirmwareVersionRequest {
     pub image: u8,
 }
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for GetFirmwareVersionRequest {
     const REQUEST_OPCODE: u8 = 0x0B;
     type Response = GetFirmwareVersionResponse;
 }
 
+// NRF_DFU_FIRMWARE_TYPE_*
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FirmwareType {
+    Softdevice,
+    Application,
+    Bootloader,
+    Unknown,
+}
+
+impl From<u8> for FirmwareType {
+    fn from(firmware_type: u8) -> FirmwareType {
+        match firmware_type {
+            0x00 => FirmwareType::Softdevice,
+            0x01 => FirmwareType::Application,
+            0x02 => FirmwareType::Bootloader,
+            _ => FirmwareType::Unknown,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct GetFirmwareVersionResponse {
     pub firmware_type: u8,
@@ -200,13 +299,41 @@ pub struct GetFirmwareVersionResponse {
     pub length: u32,
 }
 
+#[cfg(feature = "std")]
 impl DfuResponse<'_> for GetFirmwareVersionResponse {}
 
+impl GetFirmwareVersionResponse {
+    // Types this crate doesn't know, including the 0xFF sent for an empty slot, are Unknown.
+    pub fn firmware_type_kind(&self) -> FirmwareType {
+        FirmwareType::from(self.firmware_type)
+    }
+}
+
 // NRF_DFU_OP_ABORT
 #[derive(Serialize)]
 pub struct AbortRequest;
 
+#[cfg(feature = "std")]
 impl DfuRequest<'_> for AbortRequest {
     const REQUEST_OPCODE: u8 = 0x0C;
     type Response = NoResponse;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_hardware_version() {
+        let hardware = GetHardwareVersionResponse {
+            part: 0x52840,
+            variant: 0x41414430,
+            rom_size: 0x100000,
+            ram_size: 0x40000,
+            rom_page_size: 0x1000,
+        };
+
+        assert_eq!(hardware.part_name(), Some("nRF52840"));
+        assert_eq!(hardware.variant_string(), "AAD0");
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::codec::DfuCodec;
+
+// Codecs are used as types rather than values, so the transcript goes to one
+// process-wide sink shared by every RecordingCodec.
+static SINK: Mutex<Option<(Instant, Box<dyn Write + Send>)>> = Mutex::new(None);
+
+// Starts writing a transcript of every frame passing through a RecordingCodec to
+// `sink`, one line per frame: seconds since start, `>` for requests or `<` for
+// responses, then the unencoded frame in hex.
+pub fn start(sink: Box<dyn Write + Send>) {
+    *SINK.lock().unwrap_or_else(|err| err.into_inner()) = Some((Instant::now(), sink));
+}
+
+// Stops recording and hands back the sink, if one was set.
+pub fn stop() -> Option<Box<dyn Write + Send>> {
+    let recording = SINK.lock().unwrap_or_else(|err| err.into_inner()).take();
+    recording.map(|(_, sink)| sink)
+}
+
+// Failing to write the transcript never fails the transfer.
+fn record(direction: char, bufs: &[&[u8]]) {
+    let mut recording = SINK.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some((started, sink)) = recording.as_mut() {
+        let hex: Vec<String> = bufs.iter().flat_map(|buf| buf.iter()).map(|byte| format!("{:02X}", byte)).collect();
+        let _ = writeln!(sink, "{:>10.6} {} {}", started.elapsed().as_secs_f64(), direction, hex.join(" "));
+    }
+}
+
+// Wraps another codec and records its frames while leaving the bytes on the wire
+// unchanged, e.g. `type Codec = RecordingCodec<SlipCodec>;`.
+pub struct RecordingCodec<C: DfuCodec>(PhantomData<C>);
+
+impl<C: DfuCodec> DfuCodec for RecordingCodec<C> {
+    type Error = C::Error;
+
+    fn decoded_read<T: Read>(reader: &mut T) -> Result<Vec<u8>, C::Error> {
+        let frame = C::decoded_read(reader)?;
+        record('<', &[&frame]);
+        Ok(frame)
+    }
+
+    fn resync<T: Read>(reader: &mut T) -> Result<(), C::Error> {
+        C::resync(reader)
+    }
+
+    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> Result<usize, C::Error> {
+        record('>', &[buf]);
+        C::encoded_write(writer, buf)
+    }
+
+    fn encoded_write_chained<T: Write>(writer: &mut T, bufs: &[&[u8]]) -> Result<usize, C::Error> {
+        record('>', bufs);
+        C::encoded_write_chained(writer, bufs)
+    }
+
+    fn encoded_write_into<T: Write>(
+        writer: &mut T,
+        bufs: &[&[u8]],
+        scratch: &mut Vec<u8>,
+    ) -> Result<usize, C::Error> {
+        record('>', bufs);
+        C::encoded_write_into(writer, bufs, scratch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::slip::SlipCodec;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_frames_without_changing_them() {
+        let transcript = SharedBuffer::default();
+        start(Box::new(transcript.clone()));
+
+        let mut recorded = vec![];
+        RecordingCodec::<SlipCodec>::encoded_write_chained(&mut recorded, &[&[0x09], &[0xC0]]).unwrap();
+        let mut plain = vec![];
+        SlipCodec::encoded_write_chained(&mut plain, &[&[0x09], &[0xC0]]).unwrap();
+        assert_eq!(recorded, plain);
+
+        let mut input: &[u8] = &[0x60, 0x09, 0x01, 0xDB, 0xDC, 0xC0];
+        let frame = RecordingCodec::<SlipCodec>::decoded_read(&mut input).unwrap();
+        assert_eq!(frame, vec![0x60, 0x09, 0x01, 0xC0]);
+        assert!(stop().is_some());
+
+        let transcript = String::from_utf8(transcript.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = transcript.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("> 09 C0"));
+        assert!(lines[1].ends_with("< 60 09 01 C0"));
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::updater::ResetMode;
+
+const PULSE: Duration = Duration::from_millis(50);
+const SETTLE: Duration = Duration::from_millis(100);
+
+// The modem control lines of a serial link. `true` asserts a line, which drives the
+// pin low on the usual inverting USB-UART adapters. Links without them, like a plain
+// TCP bridge, keep the defaults and report Unsupported.
+pub trait ControlLines {
+    fn set_dtr(&mut self, _asserted: bool) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn set_rts(&mut self, _asserted: bool) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl ControlLines for TcpStream {}
+
+#[cfg(feature = "serialport")]
+impl ControlLines for dyn serialport::SerialPort {
+    fn set_dtr(&mut self, asserted: bool) -> std::io::Result<()> {
+        Ok(self.write_data_terminal_ready(asserted)?)
+    }
+
+    fn set_rts(&mut self, asserted: bool) -> std::io::Result<()> {
+        Ok(self.write_request_to_send(asserted)?)
+    }
+}
+
+// Common ways of getting a board into its bootloader or application from the host end
+// of a serial link, for NordicDevice impls to reuse from `reset`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SerialReset {
+    // RTS drives nRESET and DTR holds the DFU button pin: pulse RTS, with DTR asserted
+    // when the bootloader is wanted. The auto-reset wiring of most USB-UART adapters.
+    DtrRts,
+    // A single DTR pulse on boards that reset on DTR alone and wait in the bootloader
+    // after every reset.
+    PulseDtr,
+    // Bytes that make the running application restart into the bootloader, e.g. a
+    // buttonless DFU command in whatever framing the application expects. Written as
+    // they are, and only when the bootloader is wanted.
+    Command(Vec<u8>),
+}
+
+impl SerialReset {
+    pub fn apply<P: ControlLines + Write + ?Sized>(&self, port: &mut P, mode: ResetMode) -> std::io::Result<()> {
+        match self {
+            SerialReset::DtrRts => {
+                port.set_dtr(mode == ResetMode::Bootloader)?;
+                port.set_rts(true)?;
+                thread::sleep(PULSE);
+                port.set_rts(false)?;
+                thread::sleep(SETTLE);
+                port.set_dtr(false)
+            }
+            SerialReset::PulseDtr => {
+                port.set_dtr(true)?;
+                thread::sleep(PULSE);
+                port.set_dtr(false)?;
+                thread::sleep(SETTLE);
+                Ok(())
+            }
+            SerialReset::Command(command) => {
+                if mode == ResetMode::Bootloader {
+                    port.write_all(command)?;
+                    port.flush()?;
+                    thread::sleep(SETTLE);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakePort {
+        lines: Vec<(&'static str, bool)>,
+        written: Vec<u8>,
+    }
+
+    impl ControlLines for FakePort {
+        fn set_dtr(&mut self, asserted: bool) -> std::io::Result<()> {
+            self.lines.push(("DTR", asserted));
+            Ok(())
+        }
+
+        fn set_rts(&mut self, asserted: bool) -> std::io::Result<()> {
+            self.lines.push(("RTS", asserted));
+            Ok(())
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drives_lines_for_each_mode() {
+        let mut port = FakePort::default();
+        SerialReset::DtrRts.apply(&mut port, ResetMode::Bootloader).unwrap();
+        assert_eq!(port.lines, [("DTR", true), ("RTS", true), ("RTS", false), ("DTR", false)]);
+
+        let mut port = FakePort::default();
+        SerialReset::DtrRts.apply(&mut port, ResetMode::Application).unwrap();
+        assert_eq!(port.lines[0], ("DTR", false));
+
+        let command = SerialReset::Command(vec![0x01, 0xC0]);
+        let mut port = FakePort::default();
+        command.apply(&mut port, ResetMode::Application).unwrap();
+        command.apply(&mut port, ResetMode::Bootloader).unwrap();
+        assert_eq!(port.written, [0x01, 0xC0]);
+        assert!(port.lines.is_empty());
+    }
+}
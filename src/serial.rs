@@ -0,0 +1,174 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use serialport::{ClearBuffer, SerialPort, SerialPortInfo, SerialPortType};
+
+use crate::reset::SerialReset;
+use crate::slip::SlipCodec;
+use crate::updater::{LinkInfo, NordicDevice, ResetMode};
+
+type ResetFn = Box<dyn FnMut(&mut dyn SerialPort, ResetMode) + Send>;
+
+// Nordic's USB vendor ID, which the nRF52840's USB bootloader enumerates under.
+pub const NORDIC_VID: u16 = 0x1915;
+
+// A port found by `SerialDevice::list`. USB details are only there for USB ports,
+// including the CDC ACM port of a USB bootloader; open any of them by `name`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortInfo {
+    pub name: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+    pub product: Option<String>,
+}
+
+impl PortInfo {
+    pub fn is_nordic(&self) -> bool {
+        self.vid == Some(NORDIC_VID)
+    }
+}
+
+impl From<SerialPortInfo> for PortInfo {
+    fn from(info: SerialPortInfo) -> PortInfo {
+        let mut port = PortInfo {
+            name: info.port_name,
+            vid: None,
+            pid: None,
+            serial_number: None,
+            product: None,
+        };
+        if let SerialPortType::UsbPort(usb) = info.port_type {
+            port.vid = Some(usb.vid);
+            port.pid = Some(usb.pid);
+            port.serial_number = usb.serial_number;
+            port.product = usb.product;
+        }
+        port
+    }
+}
+
+// A NordicDevice over a serial port, speaking SLIP.
+//
+// The default reset assumes the usual auto-reset wiring of USB-UART adapters,
+// `SerialReset::DtrRts`. Boards wired differently can pick another sequence with
+// `set_reset_sequence`, or supply their own with `set_reset`.
+pub struct SerialDevice {
+    port: Box<dyn SerialPort>,
+    sequence: SerialReset,
+    reset: Option<ResetFn>,
+}
+
+impl SerialDevice {
+    pub fn new(port: Box<dyn SerialPort>) -> Self {
+        SerialDevice {
+            port,
+            sequence: SerialReset::DtrRts,
+            reset: None,
+        }
+    }
+
+    // Every serial port on the host, to pick one from before `open`.
+    pub fn list() -> serialport::Result<Vec<PortInfo>> {
+        Ok(serialport::available_ports()?.into_iter().map(PortInfo::from).collect())
+    }
+
+    pub fn open(path: &str, baud_rate: u32) -> serialport::Result<Self> {
+        let port = serialport::new(path, baud_rate).timeout(Duration::from_secs(5)).open()?;
+        Ok(Self::new(port))
+    }
+
+    pub fn set_reset_sequence(&mut self, sequence: SerialReset) {
+        self.sequence = sequence;
+    }
+
+    pub fn set_reset<F: FnMut(&mut dyn SerialPort, ResetMode) + Send + 'static>(&mut self, reset: F) {
+        self.reset = Some(Box::new(reset));
+    }
+
+    pub fn port(&mut self) -> &mut dyn SerialPort {
+        self.port.as_mut()
+    }
+
+    pub fn into_inner(self) -> Box<dyn SerialPort> {
+        self.port
+    }
+}
+
+impl Read for SerialDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl NordicDevice for SerialDevice {
+    type Codec = SlipCodec;
+
+    // Reset has no way to report failure, so a port that rejects the control lines
+    // shows up as the bootloader not answering afterwards.
+    fn reset(&mut self, mode: ResetMode) {
+        match &mut self.reset {
+            Some(reset) => reset(self.port.as_mut(), mode),
+            None => {
+                let _ = self.sequence.apply(self.port.as_mut(), mode);
+            }
+        }
+    }
+
+    fn drain_input(&mut self) -> std::io::Result<()> {
+        self.port.clear(ClearBuffer::Input)?;
+        Ok(())
+    }
+
+    fn link_info(&self) -> LinkInfo {
+        LinkInfo::Serial {
+            baud: self.port.baud_rate().unwrap_or(0),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.port.set_timeout(timeout)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialport::UsbPortInfo;
+
+    use super::*;
+
+    #[test]
+    fn port_info_keeps_usb_details() {
+        let usb = SerialPortInfo {
+            port_name: "/dev/ttyACM0".to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: NORDIC_VID,
+                pid: 0x521F,
+                serial_number: Some("C1A2".to_string()),
+                manufacturer: None,
+                product: Some("Open DFU Bootloader".to_string()),
+            }),
+        };
+        let port = PortInfo::from(usb);
+        assert_eq!((port.name.as_str(), port.pid), ("/dev/ttyACM0", Some(0x521F)));
+        assert!(port.is_nordic());
+
+        let uart = PortInfo::from(SerialPortInfo {
+            port_name: "/dev/ttyS0".to_string(),
+            port_type: SerialPortType::PciPort,
+        });
+        assert_eq!(uart.vid, None);
+        assert!(!uart.is_nordic());
+    }
+}
@@ -1,64 +1,665 @@
-use std::iter;
-use std::io::{Read, Write};
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{BufRead, Read, Write};
 
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[cfg(feature = "async")]
+use crate::codec::AsyncDfuCodec;
+#[cfg(feature = "std")]
 use crate::codec::DfuCodec;
 
-const SLIP_END: u8 = 0xC0;
+pub(crate) const SLIP_END: u8 = 0xC0;
 const SLIP_ESC: u8 = 0xDB;
 const SLIP_ESC_END: [u8; 2] = [0xDB, 0xDC];
 const SLIP_ESC_ESC: [u8; 2] = [0xDB, 0xDD];
 
+// DFU responses are a few dozen bytes; this only guards against a peer that never
+// sends END.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 4096;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SlipError {
+    MissingSentinel(u8),
+    InvalidEscape(u8),
+    FrameTooLong(usize),
+    Unterminated,
+    TrailingData,
+}
+
+impl fmt::Display for SlipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SlipError::MissingSentinel(byte) => write!(f, "expected frame to start with 0x60, got {:#04x}", byte),
+            SlipError::InvalidEscape(byte) => write!(f, "invalid SLIP escape sequence 0xDB {:#04x}", byte),
+            SlipError::FrameTooLong(max_frame_len) => write!(f, "SLIP frame exceeds {} bytes", max_frame_len),
+            SlipError::Unterminated => write!(f, "SLIP frame is missing its END byte"),
+            SlipError::TrailingData => write!(f, "data follows the END byte of the SLIP frame"),
+        }
+    }
+}
+
+impl SlipError {
+    // Whether the error cut a frame short, leaving the rest of it still to be read.
+    pub fn is_mid_frame(&self) -> bool {
+        matches!(self, SlipError::InvalidEscape(_) | SlipError::FrameTooLong(_))
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SlipError {}
+
+#[cfg(feature = "std")]
+impl From<SlipError> for std::io::Error {
+    fn from(err: SlipError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+// Expects every frame to open with 0x60, the response opcode of Nordic's serial DFU
+// transport, and rejects anything else. Use PlainSlipCodec for bridges and forks that
+// send bare SLIP frames.
+//
+// Both codecs read a byte at a time; wrap unbuffered ports in a BufferedDevice, or feed a
+// SlipDecoder directly.
 pub struct SlipCodec;
 
+// SLIP without the 0x60 check: any byte other than END starts a frame, and empty
+// frames from a leading END are skipped. Frames are handed on as received; responses
+// of the form `[opcode, result, payload...]`, missing the 0x60, are still accepted by
+// Updater and DfuClient.
+pub struct PlainSlipCodec;
+
+#[cfg(feature = "std")]
 impl DfuCodec for SlipCodec {
+    type Error = std::io::Error;
+
+    fn resync<T: Read>(reader: &mut T) -> std::io::Result<()> {
+        skip_to_end(reader)
+    }
+
     fn decoded_read<T: Read>(reader: &mut T) -> std::io::Result<Vec<u8>> {
-        let mut data = vec![];
-        let mut byte = [0u8; 1];
-
-        reader.read_exact(&mut byte)?;
-        if byte[0] == 0x60 {
-            loop {
-                reader.read_exact(&mut byte)?;
-                match byte[0] {
-                    SLIP_ESC => {
-                        reader.read_exact(&mut byte)?;
-                        match byte[0] {
-                            0xDC => data.push(SLIP_END),
-                            0xDD => data.push(SLIP_ESC),
-                            _ => {}
-                        }
-                    }
+        read_frame(reader, SlipDecoder::with_sentinel())
+    }
 
-                    SLIP_END => {
-                        return Ok(data);
-                    }
+    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> std::io::Result<usize> {
+        Self::encoded_write_chained(writer, &[buf])
+    }
+
+    fn encoded_write_chained<T: Write>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize> {
+        write_frame(writer, &encode_chained(bufs))
+    }
+
+    fn encoded_write_into<T: Write>(writer: &mut T, bufs: &[&[u8]], scratch: &mut Vec<u8>) -> std::io::Result<usize> {
+        encode_chained_into(scratch, bufs);
+        write_frame(writer, scratch)
+    }
+}
+
+#[cfg(feature = "std")]
+impl DfuCodec for PlainSlipCodec {
+    type Error = std::io::Error;
+
+    fn resync<T: Read>(reader: &mut T) -> std::io::Result<()> {
+        skip_to_end(reader)
+    }
+
+    fn decoded_read<T: Read>(reader: &mut T) -> std::io::Result<Vec<u8>> {
+        read_frame(reader, SlipDecoder::default())
+    }
+
+    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> std::io::Result<usize> {
+        Self::encoded_write_chained(writer, &[buf])
+    }
+
+    fn encoded_write_chained<T: Write>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize> {
+        write_frame(writer, &encode_chained(bufs))
+    }
+
+    fn encoded_write_into<T: Write>(writer: &mut T, bufs: &[&[u8]], scratch: &mut Vec<u8>) -> std::io::Result<usize> {
+        encode_chained_into(scratch, bufs);
+        write_frame(writer, scratch)
+    }
+}
+
+// SLIP with a CRC-16/CCITT-FALSE appended to every payload, little endian, for serial
+// transports that checksum their frames. Responses must carry a matching CRC, which is
+// stripped before they reach the updater.
+pub struct SerialDfuCodec;
+
+#[cfg(feature = "std")]
+impl DfuCodec for SerialDfuCodec {
+    type Error = std::io::Error;
+
+    fn resync<T: Read>(reader: &mut T) -> std::io::Result<()> {
+        skip_to_end(reader)
+    }
+
+    fn decoded_read<T: Read>(reader: &mut T) -> std::io::Result<Vec<u8>> {
+        let mut frame = read_frame(reader, SlipDecoder::default())?;
+        if frame.len() < 2 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame too short for a CRC16"));
+        }
+
+        let payload_len = frame.len() - 2;
+        let received = u16::from_le_bytes([frame[payload_len], frame[payload_len + 1]]);
+        let computed = crc16(0xFFFF, &frame[..payload_len]);
+        if received != computed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("CRC16 mismatch: frame has {:#06x}, computed {:#06x}", received, computed),
+            ));
+        }
+        frame.truncate(payload_len);
+        Ok(frame)
+    }
+
+    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> std::io::Result<usize> {
+        Self::encoded_write_chained(writer, &[buf])
+    }
+
+    fn encoded_write_chained<T: Write>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize> {
+        Self::encoded_write_into(writer, bufs, &mut vec![])
+    }
+
+    fn encoded_write_into<T: Write>(writer: &mut T, bufs: &[&[u8]], scratch: &mut Vec<u8>) -> std::io::Result<usize> {
+        let crc = bufs.iter().fold(0xFFFF, |crc, buf| crc16(crc, buf)).to_le_bytes();
+        let mut with_crc = bufs.to_vec();
+        with_crc.push(&crc);
+
+        encode_chained_into(scratch, &with_crc);
+        write_frame(writer, scratch)
+    }
+}
+
+// CRC-16/CCITT-FALSE as computed by the SDK's crc16_compute; start from 0xFFFF.
+#[cfg(feature = "std")]
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc = crc.rotate_left(8) ^ u16::from(byte);
+        crc ^= (crc & 0xFF) >> 4;
+        crc ^= crc << 12;
+        crc ^= (crc & 0xFF) << 5;
+    }
+    crc
+}
+
+#[cfg(feature = "async")]
+impl AsyncDfuCodec for SlipCodec {
+    async fn decoded_read<T: AsyncRead + Unpin>(reader: &mut T) -> std::io::Result<Vec<u8>> {
+        read_frame_async(reader, SlipDecoder::with_sentinel()).await
+    }
+
+    async fn encoded_write<T: AsyncWrite + Unpin>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize> {
+        write_frame_async(writer, bufs, &mut vec![]).await
+    }
+
+    async fn encoded_write_into<T: AsyncWrite + Unpin>(
+        writer: &mut T,
+        bufs: &[&[u8]],
+        scratch: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        write_frame_async(writer, bufs, scratch).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncDfuCodec for PlainSlipCodec {
+    async fn decoded_read<T: AsyncRead + Unpin>(reader: &mut T) -> std::io::Result<Vec<u8>> {
+        read_frame_async(reader, SlipDecoder::default()).await
+    }
+
+    async fn encoded_write<T: AsyncWrite + Unpin>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize> {
+        write_frame_async(writer, bufs, &mut vec![]).await
+    }
+
+    async fn encoded_write_into<T: AsyncWrite + Unpin>(
+        writer: &mut T,
+        bufs: &[&[u8]],
+        scratch: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        write_frame_async(writer, bufs, scratch).await
+    }
+}
+
+#[cfg(feature = "std")]
+fn read_frame<T: Read>(reader: &mut T, mut decoder: SlipDecoder) -> std::io::Result<Vec<u8>> {
+    decoder.read_frame(&mut ByteReader { reader, byte: None })
+}
+
+// Lends a plain reader to SlipDecoder::read_frame one byte at a time. Codecs keep no
+// buffer between frames, so reading further ahead would lose the start of the next
+// one; a reader that is already buffered, like BufferedDevice, makes this cheap.
+#[cfg(feature = "std")]
+struct ByteReader<'a, T: Read> {
+    reader: &'a mut T,
+    byte: Option<[u8; 1]>,
+}
+
+#[cfg(feature = "std")]
+impl<T: Read> Read for ByteReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = Read::read(&mut self.fill_buf()?, buf)?;
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Read> BufRead for ByteReader<'_, T> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        while self.byte.is_none() {
+            let mut byte = [0u8; 1];
+            match self.reader.read(&mut byte) {
+                Ok(0) => return Ok(&[]),
+                Ok(_) => self.byte = Some(byte),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.byte.as_ref().map_or(&[], |byte| &byte[..]))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt > 0 {
+            self.byte = None;
+        }
+    }
+}
+
+// Reads up to and including the next END. Running out of input first just means
+// the rest of the broken frame never arrived.
+#[cfg(feature = "std")]
+fn skip_to_end<T: Read>(reader: &mut T) -> std::io::Result<()> {
+    let mut byte = [0u8; 1];
+    loop {
+        match reader.read_exact(&mut byte) {
+            Ok(()) if byte[0] == SLIP_END => return Ok(()),
+            Ok(()) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                return Ok(())
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn write_frame<T: Write>(writer: &mut T, slip_frame: &[u8]) -> std::io::Result<usize> {
+    writer.write_all(slip_frame)?;
+    writer.flush()?;
+    Ok(slip_frame.len())
+}
+
+#[cfg(feature = "async")]
+async fn read_frame_async<T: AsyncRead + Unpin>(reader: &mut T, mut decoder: SlipDecoder) -> std::io::Result<Vec<u8>> {
+    loop {
+        if let Some(frame) = decoder.push_byte(reader.read_u8().await?)? {
+            return Ok(frame);
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+async fn write_frame_async<T: AsyncWrite + Unpin>(
+    writer: &mut T,
+    bufs: &[&[u8]],
+    scratch: &mut Vec<u8>,
+) -> std::io::Result<usize> {
+    encode_chained_into(scratch, bufs);
+
+    writer.write_all(scratch).await?;
+    writer.flush().await?;
+    Ok(scratch.len())
+}
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    encode_chained(&[data])
+}
+
+// Decodes one complete frame, END byte included. Leading END bytes are skipped, and
+// unlike SlipCodec no 0x60 sentinel or length cap applies.
+pub fn decode(frame: &[u8]) -> Result<Vec<u8>, SlipError> {
+    let mut decoder = SlipDecoder::new();
+    decoder.set_max_frame_len(usize::MAX);
+
+    for (index, &byte) in frame.iter().enumerate() {
+        if let Some(data) = decoder.push_byte(byte)? {
+            if index + 1 != frame.len() {
+                return Err(SlipError::TrailingData);
+            }
+            return Ok(data);
+        }
+    }
+    Err(SlipError::Unterminated)
+}
+
+fn encode_chained(bufs: &[&[u8]]) -> Vec<u8> {
+    let mut slip_frame = vec![];
+    encode_chained_into(&mut slip_frame, bufs);
+    slip_frame
+}
+
+// Clears `buf` and writes the SLIP frame for `data` into it, so a buffer kept across
+// frames stops allocating once it has grown to the largest one.
+pub fn encode_into(buf: &mut Vec<u8>, data: &[u8]) {
+    encode_chained_into(buf, &[data]);
+}
+
+pub(crate) fn encode_chained_into(buf: &mut Vec<u8>, bufs: &[&[u8]]) {
+    buf.clear();
+    for &byte in bufs.iter().flat_map(|data| data.iter()) {
+        match byte {
+            SLIP_END => buf.extend_from_slice(&SLIP_ESC_END),
+            SLIP_ESC => buf.extend_from_slice(&SLIP_ESC_ESC),
+            x => buf.push(x),
+        }
+    }
+    buf.push(SLIP_END);
+}
+
+// Incremental decoder for callers that read in bulk: push whatever a read returned and
+// take the frames it completes. PlainSlipCodec's rules apply unless built with
+// `with_sentinel`, which matches SlipCodec.
+pub struct SlipDecoder {
+    data: Vec<u8>,
+    escaped: bool,
+    sentinel: bool,
+    max_frame_len: usize,
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Default for SlipDecoder {
+    fn default() -> Self {
+        SlipDecoder {
+            data: vec![],
+            escaped: false,
+            sentinel: false,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            frames: VecDeque::new(),
+        }
+    }
+}
 
-                    x => {
-                        data.push(x);
+impl SlipDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sentinel() -> Self {
+        SlipDecoder {
+            sentinel: true,
+            ..Default::default()
+        }
+    }
+
+    // Frames that grow past `max_frame_len` decoded bytes fail with InvalidData.
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    // On error the partial frame is dropped and the rest of `bytes` discarded; frames
+    // completed before it stay queued.
+    #[cfg(feature = "std")]
+    pub fn push(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        for &byte in bytes {
+            if let Some(frame) = self.push_byte(byte)? {
+                self.frames.push_back(frame);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+
+    // Decodes straight out of the reader's buffer, consuming no more than the frame, so
+    // a BufReader over a serial port costs one read per buffer fill instead of per byte.
+    #[cfg(feature = "std")]
+    pub fn read_frame<R: BufRead>(&mut self, reader: &mut R) -> std::io::Result<Vec<u8>> {
+        if let Some(frame) = self.next_frame() {
+            return Ok(frame);
+        }
+
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let len = buf.iter().position(|&byte| byte == SLIP_END).map_or(buf.len(), |end| end + 1);
+            let mut frame = None;
+            let mut failed = None;
+            for &byte in &buf[..len] {
+                match self.push_byte(byte) {
+                    Ok(Some(complete)) => frame = Some(complete),
+                    Ok(None) => {}
+                    Err(err) => {
+                        failed = Some(err);
+                        break;
                     }
                 }
             }
+            // Up to and including END, so a broken frame isn't read again next time.
+            reader.consume(len);
+            if let Some(err) = failed {
+                return Err(err.into());
+            }
+
+            if let Some(frame) = frame {
+                return Ok(frame);
+            }
+        }
+    }
+
+    pub(crate) fn push_byte(&mut self, byte: u8) -> Result<Option<Vec<u8>>, SlipError> {
+        if self.data.is_empty() && !self.escaped {
+            if self.sentinel {
+                if byte != 0x60 {
+                    return Err(SlipError::MissingSentinel(byte));
+                }
+                self.data.push(byte);
+                return Ok(None);
+            }
+            if byte == SLIP_END {
+                return Ok(None);
+            }
+        }
+
+        let decoded = if self.escaped {
+            self.escaped = false;
+            match byte {
+                0xDC => SLIP_END,
+                0xDD => SLIP_ESC,
+                _ => {
+                    self.data.clear();
+                    return Err(SlipError::InvalidEscape(byte));
+                }
+            }
         } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Expected byte: 0x60",
-            ))
+            match byte {
+                SLIP_ESC => {
+                    self.escaped = true;
+                    return Ok(None);
+                }
+                SLIP_END => return Ok(Some(core::mem::take(&mut self.data))),
+                x => x,
+            }
+        };
+
+        if self.data.len() >= self.max_frame_len {
+            self.data.clear();
+            return Err(SlipError::FrameTooLong(self.max_frame_len));
         }
+        self.data.push(decoded);
+        Ok(None)
     }
+}
 
-    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> std::io::Result<usize> {
-        let slip_frame = buf
-            .iter()
-            .flat_map(|x| match *x {
-                SLIP_END => Vec::from(SLIP_ESC_END),
-                SLIP_ESC => Vec::from(SLIP_ESC_ESC),
-                _ => Vec::from([*x]),
-            })
-            .chain(iter::once(SLIP_END))
-            .collect::<Vec<_>>();
-
-        let size = writer.write(&slip_frame)?;
-        writer.flush()?;
-        Ok(size)
-    }
-}
\ No newline at end of file
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chained_write_matches_single_write() {
+        let mut single = vec![];
+        <SlipCodec as DfuCodec>::encoded_write(&mut single, &[0x08, 0xC0, 0x01, 0xDB]).unwrap();
+
+        let mut chained = vec![];
+        SlipCodec::encoded_write_chained(&mut chained, &[&[0x08], &[0xC0, 0x01, 0xDB]]).unwrap();
+
+        assert_eq!(single, chained);
+        assert_eq!(chained, vec![0x08, 0xDB, 0xDC, 0x01, 0xDB, 0xDD, 0xC0]);
+    }
+
+    #[test]
+    fn decoder_reassembles_frames_across_pushes() {
+        let mut decoder = SlipDecoder::with_sentinel();
+        decoder.push(&[0x60, 0x09, 0x01]).unwrap();
+        assert_eq!(decoder.next_frame(), None);
+
+        decoder.push(&[0xDB, 0xDD, 0xC0, 0x60, 0x03, 0xC0]).unwrap();
+        assert_eq!(decoder.next_frame(), Some(vec![0x60, 0x09, 0x01, 0xDB]));
+        assert_eq!(decoder.next_frame(), Some(vec![0x60, 0x03]));
+        assert_eq!(decoder.next_frame(), None);
+    }
+
+    #[test]
+    fn decoder_reads_one_frame_from_a_buffer() {
+        let mut reader = std::io::BufReader::new(&[0x60, 0x09, 0xC0, 0x60, 0x04, 0xC0][..]);
+        let mut decoder = SlipDecoder::with_sentinel();
+
+        assert_eq!(decoder.read_frame(&mut reader).unwrap(), vec![0x60, 0x09]);
+        assert_eq!(reader.fill_buf().unwrap(), &[0x60, 0x04, 0xC0]);
+    }
+
+    #[test]
+    fn decoder_reads_past_a_broken_frame() {
+        let mut reader = std::io::BufReader::new(&[0x60, 0xDB, 0x00, 0x01, 0xC0, 0x60, 0x04, 0xC0][..]);
+        let mut decoder = SlipDecoder::with_sentinel();
+
+        assert!(decoder.read_frame(&mut reader).is_err());
+        assert_eq!(decoder.read_frame(&mut reader).unwrap(), vec![0x60, 0x04]);
+    }
+
+    #[test]
+    fn codec_reads_no_further_than_its_frame() {
+        let mut reader = &[0x60, 0x09, 0xC0, 0x60, 0x04, 0xC0][..];
+        assert_eq!(<SlipCodec as DfuCodec>::decoded_read(&mut reader).unwrap(), vec![0x60, 0x09]);
+        assert_eq!(reader, &[0x60, 0x04, 0xC0]);
+    }
+
+    // Accepts at most three bytes per write call.
+    struct TrickleWriter(Vec<u8>);
+
+    impl Write for TrickleWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn decode_reverses_encode() {
+        let data = [0x60, 0xC0, 0x01, 0xDB, 0x02];
+        assert_eq!(decode(&encode(&data)), Ok(data.to_vec()));
+
+        assert_eq!(decode(&[0x01, 0x02]), Err(SlipError::Unterminated));
+        assert_eq!(decode(&[0x01, 0xC0, 0x02]), Err(SlipError::TrailingData));
+        assert_eq!(decode(&[0x01, 0xDB, 0x02, 0xC0]), Err(SlipError::InvalidEscape(0x02)));
+    }
+
+    #[test]
+    fn encode_into_reuses_buffer() {
+        let mut buf = vec![0xFF; 16];
+        encode_into(&mut buf, &[0x08, 0xC0]);
+        assert_eq!(buf, vec![0x08, 0xDB, 0xDC, 0xC0]);
+
+        let mut written = vec![];
+        <SlipCodec as DfuCodec>::encoded_write_into(&mut written, &[&[0x08], &[0xDB]], &mut buf).unwrap();
+        assert_eq!(written, vec![0x08, 0xDB, 0xDD, 0xC0]);
+    }
+
+    #[test]
+    fn short_writes_are_completed() {
+        let mut writer = TrickleWriter(vec![]);
+        let size = <SlipCodec as DfuCodec>::encoded_write(&mut writer, &[0x08, 0x01, 0x02, 0x03, 0xC0]).unwrap();
+
+        assert_eq!(size, 7);
+        assert_eq!(writer.0, vec![0x08, 0x01, 0x02, 0x03, 0xDB, 0xDC, 0xC0]);
+    }
+
+    #[test]
+    fn invalid_escape_is_reported() {
+        let mut input: &[u8] = &[0x60, 0x09, 0xDB, 0x00, 0x01, 0xC0];
+        let error = <SlipCodec as DfuCodec>::decoded_read(&mut input).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn resync_skips_rest_of_broken_frame() {
+        let mut input: &[u8] = &[0x60, 0x09, 0xDB, 0x00, 0x01, 0xC0, 0x60, 0x09, 0xC0];
+        assert!(<SlipCodec as DfuCodec>::decoded_read(&mut input).is_err());
+
+        SlipCodec::resync(&mut input).unwrap();
+        assert_eq!(<SlipCodec as DfuCodec>::decoded_read(&mut input).unwrap(), vec![0x60, 0x09]);
+
+        SlipCodec::resync(&mut input).unwrap();
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut input = vec![0x60; DEFAULT_MAX_FRAME_LEN + 1];
+        input.push(0xC0);
+        let error = <SlipCodec as DfuCodec>::decoded_read(&mut &input[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut decoder = SlipDecoder::new();
+        decoder.set_max_frame_len(2);
+        assert!(decoder.push(&[0x01, 0x02, 0xC0]).is_ok());
+        assert!(decoder.push(&[0x01, 0x02, 0x03, 0xC0]).is_err());
+    }
+
+    #[test]
+    fn serial_codec_appends_and_checks_crc16() {
+        assert_eq!(crc16(0xFFFF, b"123456789"), 0x29B1);
+
+        let mut written = vec![];
+        SerialDfuCodec::encoded_write_chained(&mut written, &[&[0x09], &[0x01]]).unwrap();
+        let crc = crc16(0xFFFF, &[0x09, 0x01]).to_le_bytes();
+        assert_eq!(written, encode(&[0x09, 0x01, crc[0], crc[1]]));
+
+        let response = [0x60, 0x09, 0x01, 0x01];
+        let mut frame = response.to_vec();
+        frame.extend_from_slice(&crc16(0xFFFF, &response).to_le_bytes());
+        assert_eq!(SerialDfuCodec::decoded_read(&mut &encode(&frame)[..]).unwrap(), response.to_vec());
+
+        frame[1] ^= 0xFF;
+        let error = SerialDfuCodec::decoded_read(&mut &encode(&frame)[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn plain_codec_accepts_frames_without_sentinel() {
+        let mut input: &[u8] = &[0xC0, 0x09, 0xDB, 0xDC, 0x01, 0xC0];
+        assert_eq!(<PlainSlipCodec as DfuCodec>::decoded_read(&mut input).unwrap(), vec![0x09, 0xC0, 0x01]);
+
+        let mut input: &[u8] = &[0x09, 0x01, 0xC0];
+        assert!(<SlipCodec as DfuCodec>::decoded_read(&mut input).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::slip::SlipCodec;
+use crate::updater::{NordicDevice, ResetMode};
+
+type ResetFn = Box<dyn FnMut(&mut TcpStream, ResetMode) + Send>;
+
+// A NordicDevice for a UART exposed over TCP by a bridge such as ser2net or esp-link.
+// Bridges differ in how, or whether, they can reset the target, so reset does nothing
+// unless given a closure, e.g. one that sends the bridge's RFC 2217 or vendor command,
+// or applies a `SerialReset::Command` that asks the application for its bootloader.
+pub struct TcpDevice {
+    stream: TcpStream,
+    peer: SocketAddr,
+    reset: Option<ResetFn>,
+}
+
+impl TcpDevice {
+    pub fn new(stream: TcpStream) -> std::io::Result<Self> {
+        // Requests are a few bytes each and wait on their response, so Nagle only adds latency.
+        stream.set_nodelay(true)?;
+        Ok(TcpDevice {
+            peer: stream.peer_addr()?,
+            stream,
+            reset: None,
+        })
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        Self::new(TcpStream::connect(addr)?)
+    }
+
+    pub fn set_reset<F: FnMut(&mut TcpStream, ResetMode) + Send + 'static>(&mut self, reset: F) {
+        self.reset = Some(Box::new(reset));
+    }
+
+    pub fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+}
+
+impl Read for TcpDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TcpDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl NordicDevice for TcpDevice {
+    type Codec = SlipCodec;
+
+    fn reset(&mut self, mode: ResetMode) {
+        if let Some(reset) = &mut self.reset {
+            reset(&mut self.stream, mode);
+        }
+    }
+
+    fn drain_input(&mut self) -> std::io::Result<()> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0u8; 256];
+        let drained = loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        drained
+    }
+
+    // Some bridges drop the connection while the target's USB port re-enumerates.
+    fn reconnect(&mut self) -> std::io::Result<()> {
+        let stream = TcpStream::connect(self.peer)?;
+        stream.set_nodelay(true)?;
+        self.stream = stream;
+        Ok(())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::slip::SlipDecoder;
+    use crate::updater::Updater;
+
+    #[test]
+    fn exchanges_frames_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bridge = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = SlipDecoder::new();
+            let mut buf = [0u8; 64];
+            while decoder.next_frame().is_none() {
+                let len = stream.read(&mut buf).unwrap();
+                decoder.push(&buf[..len]).unwrap();
+            }
+            stream.write_all(&[0x60, 0x09, 0x01, 0x7F, 0xC0]).unwrap();
+        });
+
+        let (resets, reset_modes) = mpsc::channel();
+        let mut device = TcpDevice::connect(addr).unwrap();
+        device.set_reset(move |_, mode| resets.send(mode).unwrap());
+
+        let frame = Updater::new(&mut device, false).raw_exchange(0x09, &[0x7F]).unwrap();
+        assert_eq!(frame, vec![0x60, 0x09, 0x01, 0x7F]);
+        bridge.join().unwrap();
+
+        device.reset(ResetMode::Bootloader);
+        assert_eq!(reset_modes.try_recv(), Ok(ResetMode::Bootloader));
+    }
+}
@@ -1,13 +1,21 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::io::{Read, Write};
 
 use crc::crc32;
+use serde::Serialize;
 
-use crate::archive::{FirmwareArchive, FirmwareData};
+use crate::archive::{FirmwareArchive, ImageKind, ImageSource, ModuleSelection};
+use crate::client::DfuClient;
 use crate::codec::DfuCodec;
-use crate::dfu::{DfuError, DfuRequest, DfuResponse, NoResponse, ObjectType};
+use crate::dfu::{DfuError, DfuRequest, ObjectType};
+use crate::init_packet::{InitPacket, InitPacketError, InitPacketSet};
+use crate::plan::{ImagePlan, TransferPlan};
 use crate::protocol::*;
+use crate::slip::SlipError;
 
 #[derive(Debug)]
 pub enum Error {
@@ -15,190 +23,2261 @@ pub enum Error {
     DfuError(DfuError),
     PingMismatch,
     CrcMismatch,
+    OffsetOutOfSync { reported: usize, sent: usize },
+    Cancelled { offset: u32, crc: u32, object_type: ObjectType },
+    NoBootloaderResponse,
+    OffsetMismatch { reported: usize, expected: usize },
+    InvalidInitPacket(InitPacketError),
+    Encode(bincode::Error),
+    Decode(bincode::Error),
+    UnexpectedResponse(Vec<u8>),
+    InvalidMtu(u16),
+    Timeout,
+    VerifyMismatch { offset: usize, crc: u32 },
+    ResumeMismatch { offset: usize },
+    InitPacketMismatch { image_size: usize },
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(err) => write!(f, "I/O error: {}", err),
+            Error::DfuError(err) => write!(f, "device returned an error: {}", err),
+            Error::PingMismatch => write!(f, "ping response did not match the request"),
+            Error::CrcMismatch => write!(f, "CRC reported by the device does not match the data sent"),
+            Error::OffsetOutOfSync { reported, sent } => {
+                write!(f, "device reported offset {} after {} bytes were sent", reported, sent)
+            }
+            Error::Cancelled { offset, object_type, .. } => {
+                write!(f, "transfer cancelled at offset {} of the {:?} object", offset, object_type)
+            }
+            Error::NoBootloaderResponse => write!(f, "no response from the bootloader"),
+            Error::OffsetMismatch { reported, expected } => {
+                write!(f, "device reported offset {}, expected {}", reported, expected)
+            }
+            Error::InvalidInitPacket(err) => write!(f, "invalid init packet: {}", err),
+            Error::Encode(err) => write!(f, "failed to encode request: {}", err),
+            Error::Decode(err) => write!(f, "malformed response payload: {}", err),
+            Error::UnexpectedResponse(frame) => write!(f, "unexpected response frame {:02X?}", frame),
+            Error::InvalidMtu(mtu) => write!(f, "device reported an implausible MTU of {}", mtu),
+            Error::Timeout => write!(f, "timed out waiting for the device"),
+            Error::VerifyMismatch { offset, crc } => {
+                write!(f, "device holds {} bytes with CRC {:#010x} before the final execute", offset, crc)
+            }
+            Error::ResumeMismatch { offset } => {
+                write!(f, "the {} bytes the device already holds do not match the image", offset)
+            }
+            Error::InitPacketMismatch { image_size } => {
+                write!(f, "init packet does not describe the {} byte application image", image_size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(err) => Some(err),
+            Error::DfuError(err) => Some(err),
+            Error::InvalidInitPacket(err) => Some(err),
+            Error::Encode(err) | Error::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<InitPacketError> for Error {
+    fn from(err: InitPacketError) -> Error {
+        Error::InvalidInitPacket(err)
+    }
+}
+
+// Only a frame that broke off partway leaves bytes to skip before the next one; garbage
+// ahead of a frame is already consumed, and skipping to the next END would eat the reply.
+fn breaks_frame(err: &Error) -> bool {
+    match err {
+        Error::IOError(err) => err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<SlipError>())
+            .is_some_and(SlipError::is_mid_frame),
+        _ => false,
+    }
+}
+
+// Each write is sent SLIP-escaped, so in the worst case a chunk doubles in size, and
+// one byte goes to the opcode. Anything under 4 bytes per chunk is not a real MTU.
+pub(crate) fn chunk_size_for_mtu(mtu: u16) -> Result<usize, Error> {
+    match (mtu / 2).checked_sub(1) {
+        Some(chunk_size) if chunk_size >= 4 => Ok(chunk_size as usize),
+        _ => Err(Error::InvalidMtu(mtu)),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CrcAlgorithm {
+    Ieee,
+    Castagnoli,
+}
+
+impl CrcAlgorithm {
+    fn table(self) -> &'static [u32; 256] {
+        match self {
+            CrcAlgorithm::Ieee => &crc32::IEEE_TABLE,
+            CrcAlgorithm::Castagnoli => &crc32::CASTAGNOLI_TABLE,
+        }
+    }
+
+    pub fn update(self, crc: u32, data: &[u8]) -> u32 {
+        crc32::update(crc, self.table(), data)
+    }
+
+    pub fn checksum(self, data: &[u8]) -> u32 {
+        self.update(0, data)
+    }
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlePhy {
+    Le1M,
+    Le2M,
+    LeCoded,
+}
+
+#[derive(Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LinkInfo {
+    #[default]
+    Unknown,
+    Serial { baud: u32 },
+    Ble { phy: BlePhy, connection_interval_us: u32, att_mtu: u16 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ResetMode {
+    /// Restart into the DFU bootloader, e.g. between a SoftDevice/bootloader image and
+    /// the application that follows it.
     Bootloader,
+    /// Restart into the application, for bootloaders that do not boot it on their own
+    /// after a successful transfer.
     Application,
 }
 
 pub trait NordicDevice: Read + Write {
     type Codec: DfuCodec;
     fn reset(&mut self, mode: ResetMode);
+
+    // Discards any bytes already waiting on the input, such as boot banners printed
+    // after a reset. Called before every ping of the handshake.
+    fn drain_input(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // The most object data the transport can carry in a single write, for links whose
+    // own limit is below what the bootloader's MTU implies.
+    fn max_frame(&self) -> usize {
+        usize::MAX
+    }
+
+    fn link_info(&self) -> LinkInfo {
+        LinkInfo::Unknown
+    }
+
+    // Called after the device resets back into the bootloader, for transports whose
+    // handle does not survive it: a USB serial port that re-enumerates, or a BLE link
+    // that drops. Reopen the connection here.
+    fn reconnect(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // Bounds how long a read may block before failing with ErrorKind::TimedOut, which
+    // the updater reports as Error::Timeout. Devices without a deadline can ignore it.
+    fn set_read_timeout(&mut self, _timeout: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct TransferStats {
+    pub link: LinkInfo,
+    pub protocol_version: Option<u8>,
+    pub command_bytes: usize,
+    pub data_bytes: usize,
+    pub checkpoints: u32,
+    pub retries: u32,
+    pub elapsed: Duration,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    Updated,
+    AlreadyCurrent { version: u32 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub module: ObjectType,
+    pub bytes_written: usize,
+    pub total: usize,
 }
 
 pub struct Updater<'a, T: NordicDevice> {
-    comm: &'a mut T,
+    client: DfuClient<'a, T>,
     prn: u16,
     chunk_size: usize,
     force: bool,
+    ping_attempts: u32,
+    ping_retry_delay: Duration,
+    cancel: Option<Arc<AtomicBool>>,
+    crc: CrcAlgorithm,
+    pipelined: bool,
+    two_phase: bool,
+    defer_execute: bool,
+    pending_commit: Option<ImageKind>,
+    auto_boot: bool,
+    progress: Option<&'a mut dyn FnMut(Progress)>,
+    max_retries: u32,
+    crc_retries: u32,
+    bootloader_reset_delay: Duration,
+    app_reset_delay: Duration,
+    chunk_size_override: Option<usize>,
+    stats: TransferStats,
+    verify: bool,
+    in_transfer: bool,
+    protocol_version: Option<u8>,
 }
 
-impl<'a, T: NordicDevice> Updater<'a, T> {
-    pub fn new(comm: &'a mut T, force: bool) -> Self {
-        Self {
-            comm,
+#[derive(Clone, Debug)]
+pub struct UpdaterBuilder {
+    force: bool,
+    prn: u16,
+    ping_attempts: u32,
+    ping_retry_delay: Duration,
+    cancel: Option<Arc<AtomicBool>>,
+    crc: CrcAlgorithm,
+    pipelined: bool,
+    two_phase: bool,
+    auto_boot: bool,
+    max_retries: u32,
+    bootloader_reset_delay: Duration,
+    app_reset_delay: Duration,
+    chunk_size_override: Option<usize>,
+    request_timeout: Duration,
+    execute_timeout: Duration,
+    verify: bool,
+}
+
+impl Default for UpdaterBuilder {
+    fn default() -> Self {
+        UpdaterBuilder {
+            force: false,
             prn: 3,
+            ping_attempts: 3,
+            ping_retry_delay: Duration::from_millis(100),
+            cancel: None,
+            crc: CrcAlgorithm::Ieee,
+            pipelined: false,
+            two_phase: false,
+            auto_boot: false,
+            max_retries: 0,
+            bootloader_reset_delay: Duration::from_millis(1000),
+            app_reset_delay: Duration::from_millis(500),
+            chunk_size_override: None,
+            request_timeout: Duration::from_secs(5),
+            execute_timeout: Duration::from_secs(30),
+            verify: true,
+        }
+    }
+}
+
+// Collects the updater's settings up front; each one mirrors the `Updater::set_*`
+// method of the same name.
+impl UpdaterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn prn(mut self, prn: u16) -> Self {
+        self.prn = prn;
+        self
+    }
+
+    pub fn ping_retries(mut self, attempts: u32, delay: Duration) -> Self {
+        self.ping_attempts = attempts.max(1);
+        self.ping_retry_delay = delay;
+        self
+    }
+
+    pub fn cancel_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    pub fn crc_algorithm(mut self, crc: CrcAlgorithm) -> Self {
+        self.crc = crc;
+        self
+    }
+
+    pub fn pipelined(mut self, pipelined: bool) -> Self {
+        self.pipelined = pipelined;
+        self
+    }
+
+    pub fn two_phase_commit(mut self, two_phase: bool) -> Self {
+        self.two_phase = two_phase;
+        self
+    }
+
+    pub fn auto_boot(mut self, auto_boot: bool) -> Self {
+        self.auto_boot = auto_boot;
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn reset_delays(mut self, bootloader_reset_delay: Duration, app_reset_delay: Duration) -> Self {
+        self.bootloader_reset_delay = bootloader_reset_delay;
+        self.app_reset_delay = app_reset_delay;
+        self
+    }
+
+    pub fn chunk_size_override(mut self, chunk_size: Option<usize>) -> Self {
+        self.chunk_size_override = chunk_size.filter(|&chunk_size| chunk_size > 0);
+        self
+    }
+
+    pub fn timeouts(mut self, request_timeout: Duration, execute_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self.execute_timeout = execute_timeout;
+        self
+    }
+
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn build<T: NordicDevice>(self, comm: &mut T) -> Updater<'_, T> {
+        let mut client = DfuClient::new(comm);
+        client.set_timeouts(self.request_timeout, self.execute_timeout);
+        Updater {
+            client,
+            prn: self.prn,
             chunk_size: 0,
-            force,
+            force: self.force,
+            ping_attempts: self.ping_attempts,
+            ping_retry_delay: self.ping_retry_delay,
+            cancel: self.cancel,
+            crc: self.crc,
+            pipelined: self.pipelined,
+            two_phase: self.two_phase,
+            defer_execute: false,
+            pending_commit: None,
+            auto_boot: self.auto_boot,
+            progress: None,
+            max_retries: self.max_retries,
+            crc_retries: 0,
+            bootloader_reset_delay: self.bootloader_reset_delay,
+            app_reset_delay: self.app_reset_delay,
+            chunk_size_override: self.chunk_size_override,
+            stats: TransferStats::default(),
+            verify: self.verify,
+            in_transfer: false,
+            protocol_version: None,
+        }
+    }
+}
+
+impl<'a, T: NordicDevice> Updater<'a, T> {
+    /// Shortcut for `UpdaterBuilder::new().force(force).build(comm)`.
+    pub fn new(comm: &'a mut T, force: bool) -> Self {
+        UpdaterBuilder::new().force(force).build(comm)
+    }
+
+    /// Before the last data object is executed, selects it again and checks the device
+    /// reports the whole image with the expected CRC. It can't be done afterwards: from
+    /// SDK 15 the bootloader resets itself once the final execute completes the update.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    fn verify_data(&mut self, len: usize, firmware_crc: u32) -> Result<(), Error> {
+        if !self.verify {
+            return Ok(());
+        }
+        let ObjectSelectResponse { offset, crc, .. } = self.request(ObjectSelectRequest {
+            object_type: ObjectType::Data,
+        })?;
+        if offset as usize != len || crc != firmware_crc {
+            return Err(Error::VerifyMismatch {
+                offset: offset as usize,
+                crc,
+            });
+        }
+        Ok(())
+    }
+
+    /// Executing an object can mean erasing and writing flash, so it gets its own,
+    /// longer timeout than every other request.
+    pub fn set_timeouts(&mut self, request_timeout: Duration, execute_timeout: Duration) {
+        self.client.set_timeouts(request_timeout, execute_timeout);
+    }
+
+    /// The underlying client, for requests Updater doesn't make itself. Leave the
+    /// device's objects as they were found if a transfer is to be resumed later.
+    pub fn client(&mut self) -> &mut DfuClient<'a, T> {
+        &mut self.client
+    }
+
+    /// Replaces the MTU-derived chunk size, for bridges that misreport their MTU.
+    /// The transport's max_frame still applies, and Some(0) is ignored.
+    pub fn set_chunk_size_override(&mut self, chunk_size: Option<usize>) {
+        self.chunk_size_override = chunk_size.filter(|&chunk_size| chunk_size > 0);
+    }
+
+    /// How long to let the device settle after activating an image: the first delay
+    /// follows a SoftDevice+bootloader image, the second a bootloader or application.
+    pub fn set_reset_delays(&mut self, bootloader_reset_delay: Duration, app_reset_delay: Duration) {
+        self.bootloader_reset_delay = bootloader_reset_delay;
+        self.app_reset_delay = app_reset_delay;
+    }
+
+    /// How many times an object that fails its CRC check is resent before giving up.
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /// Objects resent after a CRC mismatch since this updater was created.
+    pub fn crc_retries(&self) -> u32 {
+        self.crc_retries
+    }
+
+    /// Called after every chunk written and every CRC checkpoint the device confirms.
+    /// Borrowed rather than boxed so the updater still releases the device when it
+    /// goes out of use.
+    pub fn on_progress(&mut self, callback: &'a mut dyn FnMut(Progress)) {
+        self.progress = Some(callback);
+    }
+
+    fn report_progress(&mut self, module: ObjectType, bytes_written: usize, total: usize) {
+        if let Some(progress) = &mut self.progress {
+            progress(Progress {
+                module,
+                bytes_written,
+                total,
+            });
         }
     }
 
+    /// Packet receipt notifications: the device confirms offset and CRC after every
+    /// `prn` chunks. Higher values cut round trips on fast links; lower ones catch a
+    /// corrupted chunk sooner. 0 turns them off, leaving only the per-object CRC check.
+    pub fn set_prn(&mut self, prn: u16) {
+        self.prn = prn;
+    }
+
+    /// How many pings, `delay` apart, the handshake before a transfer sends before
+    /// deciding no bootloader is listening. At least one is always sent.
+    pub fn set_ping_retries(&mut self, attempts: u32, delay: Duration) {
+        self.ping_attempts = attempts.max(1);
+        self.ping_retry_delay = delay;
+    }
+
+    /// Stock bootloaders use CRC-32/IEEE; some forks switch to CRC-32C.
+    pub fn set_crc_algorithm(&mut self, crc: CrcAlgorithm) {
+        self.crc = crc;
+    }
+
+    /// With pipelining, the next burst of chunks is written while the previous packet
+    /// receipt is still in flight; that receipt is read once the burst is out, so at
+    /// most one is left unread between bursts.
+    pub fn set_pipelined(&mut self, pipelined: bool) {
+        self.pipelined = pipelined;
+    }
+
+    /// Resets into the application after it is flashed, for bootloaders that stay in
+    /// DFU mode once the transfer completes.
+    pub fn set_auto_boot(&mut self, auto_boot: bool) {
+        self.auto_boot = auto_boot;
+    }
+
+    /// With two-phase commit, `update` writes and verifies the last image but leaves its
+    /// final data object unexecuted until `commit` is called.
+    pub fn set_two_phase_commit(&mut self, two_phase: bool) {
+        self.two_phase = two_phase;
+    }
+
+    /// Once `flag` is set, the transfer stops after the chunk in flight, records the
+    /// device's committed offset and CRC, aborts, and returns `Error::Cancelled`. A
+    /// later `update` without `force` resumes from that point.
+    pub fn set_cancel_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.cancel = Some(flag);
+    }
+
+    fn cancel_requested(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|flag| flag.load(Ordering::SeqCst))
+    }
+
+    fn pause(&mut self, object_type: ObjectType) -> Result<Error, Error> {
+        let GetCrcResponse { offset, crc } = self.request(GetCrcRequest)?;
+        self.send_abort()?;
+        Ok(Error::Cancelled {
+            offset,
+            crc,
+            object_type,
+        })
+    }
+
+    fn send_abort(&mut self) -> Result<(), Error> {
+        self.in_transfer = false;
+        self.request(AbortRequest)?;
+        Ok(())
+    }
+
     fn request<'de, Request: DfuRequest<'de>>(&mut self, request: Request) -> Result<Request::Response, Error> {
-        request.dfu_write::<T, T::Codec>(self.comm)?;
-        let response = Request::Response::dfu_read::<T, T::Codec, Request>(self.comm)?;
-        Ok(response)
+        self.client.request(request)
+    }
+
+    /// Sends `[opcode, payload...]` as a single frame and returns the next decoded
+    /// frame verbatim, without checking its opcode or result code.
+    pub fn raw_exchange(&mut self, opcode: u8, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.client.raw_exchange(opcode, payload)
     }
 
-    fn write_object(&mut self, mut object_crc: u32, data: &[u8]) -> Result<u32, Error> {
+    /// Like `raw_exchange`, but checks the response answers `opcode` and succeeded, and
+    /// returns just its payload. Meant for opcodes a customised bootloader adds.
+    pub fn send_raw(&mut self, opcode: u8, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.client.send_raw(opcode, payload)
+    }
+
+    fn write_object(
+        &mut self,
+        object_type: ObjectType,
+        mut offset: usize,
+        mut object_crc: u32,
+        data: &[u8],
+        total: usize,
+    ) -> Result<u32, Error> {
         let mut prn_count = 0;
+        let mut pending_receipt = None;
 
+        // The device only sends receipts every `prn` writes, so chunks after the last one
+        // go unchecked here; transfer_source's GetCrc right after the object covers them.
         for chunk in data.chunks(self.chunk_size) {
-            object_crc = crc32::update(object_crc, &crc32::IEEE_TABLE, chunk);
-
-            if self.prn > 0 {
-                if prn_count < self.prn - 1 {
-                    prn_count += 1;
-                    self.request(ObjectWriteRequest::<NoResponse>::new(chunk))?;
-                } else {
-                    prn_count = 0;
-                    let ObjectWriteResponse { offset: _, crc } =
-                        self.request(ObjectWriteRequest::<ObjectWriteResponse>::new(chunk))?;
-                    if crc != object_crc {
-                        return Err(Error::CrcMismatch);
-                    }
+            object_crc = self.crc.update(object_crc, chunk);
+            offset += chunk.len();
+
+            let receipt_due = self.prn > 0 && {
+                prn_count += 1;
+                prn_count == self.prn
+            };
+
+            if receipt_due {
+                prn_count = 0;
+                self.client.write(chunk)?;
+                if !self.pipelined {
+                    self.read_receipt(offset, object_crc)?;
+                } else if let Some((expected, expected_crc)) = pending_receipt.replace((offset, object_crc)) {
+                    self.read_receipt(expected, expected_crc)?;
                 }
             } else {
-                self.request(ObjectWriteRequest::<NoResponse>::new(chunk))?;
+                self.client.write(chunk)?;
+            }
+            match object_type {
+                ObjectType::Command => self.stats.command_bytes += chunk.len(),
+                ObjectType::Data => self.stats.data_bytes += chunk.len(),
+            }
+            self.report_progress(object_type, offset, total);
+
+            if self.cancel_requested() {
+                if let Some((expected, expected_crc)) = pending_receipt.take() {
+                    self.read_receipt(expected, expected_crc)?;
+                }
+                return Err(self.pause(object_type)?);
             }
         }
 
+        if let Some((expected, expected_crc)) = pending_receipt {
+            self.read_receipt(expected, expected_crc)?;
+        }
+
         Ok(object_crc)
     }
 
+    fn read_receipt(&mut self, expected: usize, expected_crc: u32) -> Result<(), Error> {
+        let ObjectWriteResponse { offset, crc } = self.client.read_receipt()?;
+        if offset as usize != expected {
+            return Err(Error::OffsetMismatch {
+                reported: offset as usize,
+                expected,
+            });
+        }
+        if crc != expected_crc {
+            return Err(Error::CrcMismatch);
+        }
+        Ok(())
+    }
+
     fn transfer_object(&mut self, object_type: ObjectType, data: &[u8]) -> Result<(), Error> {
+        let mut source = data;
+        self.transfer_source(object_type, &mut source)
+    }
+
+    fn transfer_source<S: ImageSource + ?Sized>(&mut self, object_type: ObjectType, source: &mut S) -> Result<(), Error> {
+        self.in_transfer = true;
         let ObjectSelectResponse {
             max_size,
             offset,
             crc,
         } = self.request(ObjectSelectRequest { object_type })?;
 
+        let len = source.len();
+        if len == 0 {
+            return self.transfer_empty(object_type);
+        }
         let object_max_size = max_size as usize;
         let mut object_offset = offset as usize;
         let mut object_crc = crc;
-        let firmware_crc = crc32::checksum_ieee(data);
 
         if self.force {
             object_offset = 0;
             object_crc = 0;
         }
 
+        if object_offset > len {
+            return Err(Error::OffsetOutOfSync {
+                reported: object_offset,
+                sent: 0,
+            });
+        }
+
+        // The reader always sits at object_offset, and prefix_crc is the CRC of
+        // everything before it; once the whole image is sent that is the firmware CRC.
+        let mut reader = source.open()?;
+        let mut prefix_crc = self.skip(&mut reader, object_offset)?;
+        if object_crc != prefix_crc {
+            // What the device holds came from some other image. A new command object
+            // starts over from its first byte; data needs the init packet sent again.
+            if object_type == ObjectType::Data {
+                return Err(Error::ResumeMismatch { offset: object_offset });
+            }
+            drop(reader);
+            reader = source.open()?;
+            object_offset = 0;
+            object_crc = 0;
+            prefix_crc = 0;
+        }
+        let mut object = vec![];
+        let mut recreate = false;
+        let mut attempts = 0;
+        let mut deferred = false;
+
         loop {
-            if (object_offset > 0 && (object_offset % object_max_size) == 0)
-                || (object_offset == data.len() && object_crc == firmware_crc)
+            if !recreate
+                && ((object_offset > 0 && object_offset.is_multiple_of(object_max_size))
+                    || (object_offset == len && object_crc == prefix_crc))
             {
+                if object_offset == len && object_type == ObjectType::Data {
+                    self.verify_data(len, prefix_crc)?;
+                    if self.defer_execute {
+                        deferred = true;
+                        break;
+                    }
+                }
+
                 self.request(ObjectExecuteRequest)?;
 
-                if object_offset == data.len() {
+                if object_offset == len {
                     break;
                 }
             }
 
-            let mut object_end =
-                object_offset - (object_offset % object_max_size) + object_max_size;
-            if object_end > data.len() {
-                object_end = data.len();
+            let object_start = object_offset - (object_offset % object_max_size);
+            let mut object_end = object_start + object_max_size;
+            if object_end > len {
+                object_end = len;
             }
 
-            if (object_offset % object_max_size) == 0
-                || object_crc != crc32::checksum_ieee(&data[0..object_offset])
+            if recreate
+                || object_offset.is_multiple_of(object_max_size)
+                || object_crc != prefix_crc
             {
                 self.request(ObjectCreateRequest {
                     object_type,
                     object_size: (object_end - object_offset) as u32,
                 })?;
+                recreate = false;
             }
 
-            object_crc = self.write_object(object_crc, &data[object_offset..object_end])?;
+            object.resize(object_end - object_offset, 0);
+            reader.read_exact(&mut object)?;
+
+            let mismatch = match self.write_object(object_type, object_offset, object_crc, &object, len) {
+                Ok(written_crc) => {
+                    object_crc = written_crc;
+
+                    let GetCrcResponse { offset, crc } = self.request(GetCrcRequest)?;
+                    let reported = offset as usize;
+                    if reported > object_end || reported < object_offset {
+                        return Err(Error::OffsetOutOfSync {
+                            reported,
+                            sent: object_end,
+                        });
+                    }
+                    if reported == object_end {
+                        prefix_crc = self.crc.update(prefix_crc, &object);
+                    } else {
+                        // The device kept only part of what was sent; start over from the
+                        // top of the image to get back to where it is.
+                        drop(reader);
+                        reader = source.open()?;
+                        prefix_crc = self.skip(&mut reader, reported)?;
+                    }
+                    object_offset = reported;
+                    crc != object_crc
+                }
+                Err(Error::CrcMismatch) => true,
+                Err(err) => return Err(err),
+            };
+
+            if mismatch {
+                if attempts >= self.max_retries {
+                    return Err(Error::CrcMismatch);
+                }
+                attempts += 1;
+                self.crc_retries += 1;
+                self.stats.retries += 1;
 
-            let GetCrcResponse { offset, crc } = self.request(GetCrcRequest)?;
-            object_offset = offset as usize;
-            if crc != object_crc {
-                return Err(Error::CrcMismatch);
+                // Throw away whatever is left of the object on the device and send it
+                // again from its first byte.
+                self.client.device().drain_input()?;
+                self.request(ObjectSelectRequest { object_type })?;
+                drop(reader);
+                reader = source.open()?;
+                prefix_crc = self.skip(&mut reader, object_start)?;
+                object_offset = object_start;
+                object_crc = prefix_crc;
+                recreate = true;
+                continue;
             }
+
+            attempts = 0;
+            self.stats.checkpoints += 1;
+            self.report_progress(object_type, object_offset, len);
         }
 
+        // A deferred execute leaves the object open until commit.
+        self.in_transfer = deferred;
         Ok(())
     }
 
-    fn update_module(&mut self, firmware: &FirmwareData) -> Result<(), Error> {
-        match self.request(PingRequest { id: 0x7F }) {
-            Ok(PingResponse { id }) => {
-                if id != 0x7F {
-                    return Err(Error::PingMismatch);
-                }
+    // An empty image still gets an object, created with size 0 and executed, so the
+    // device sees the same sequence as for any other.
+    fn transfer_empty(&mut self, object_type: ObjectType) -> Result<(), Error> {
+        self.request(ObjectCreateRequest {
+            object_type,
+            object_size: 0,
+        })?;
+        self.request(ObjectExecuteRequest)?;
+        self.in_transfer = false;
+        Ok(())
+    }
+
+    // Reads past `len` bytes of the image, returning their CRC.
+    fn skip(&self, reader: &mut dyn Read, len: usize) -> Result<u32, Error> {
+        let mut crc = 0;
+        let mut buf = [0u8; 4096];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len());
+            reader.read_exact(&mut buf[..n])?;
+            crc = self.crc.update(crc, &buf[..n]);
+            remaining -= n;
+        }
+        Ok(crc)
+    }
+
+    /// Sends a single ping and checks the device echoes `id`. Cheap enough to probe the
+    /// link before a transfer or to keep an idle one from timing out.
+    pub fn ping(&mut self, id: u8) -> Result<(), Error> {
+        self.client.ping(id)
+    }
+
+    fn ping_handshake(&mut self) -> Result<(), Error> {
+        let mut attempt = 1;
+        loop {
+            self.client.device().drain_input()?;
+            let err = match self.ping(0x7F) {
+                Ok(()) => return Ok(()),
+                Err(Error::DfuError(DfuError::OpcodeNotSupported)) => return Ok(()),
+                Err(err) => err,
+            };
+
+            if attempt >= self.ping_attempts {
+                // Nothing that parsed as a DFU response came back, so the device is most
+                // likely running an application rather than the bootloader.
+                return match err {
+                    Error::IOError(_)
+                    | Error::Timeout
+                    | Error::UnexpectedResponse(_)
+                    | Error::DfuError(DfuError::InvalidOpcode) => Err(Error::NoBootloaderResponse),
+                    err => Err(err),
+                };
             }
-            Err(Error::DfuError(DfuError::OpcodeNotSupported)) => {}
-            Err(err) => {
-                return Err(err);
+            attempt += 1;
+            if breaks_frame(&err) {
+                T::Codec::resync(self.client.device()).map_err(Into::into)?;
             }
+            thread::sleep(self.ping_retry_delay);
         }
+    }
 
-        self.request(SetReceiptNotifyRequest { target: self.prn })?;
+    fn negotiate(&mut self) -> Result<Option<u16>, Error> {
+        self.ping_handshake()?;
 
-        match self.request(GetMtuRequest) {
-            Ok(GetMtuResponse { mtu }) => {
-                self.chunk_size = ((mtu / 2) - 1) as usize;
-            }
-            Err(Error::DfuError(DfuError::OpcodeNotSupported)) => {
-                self.chunk_size = 244;
+        // Bootloaders from before SDK 15 have no version request, and none of the other
+        // requests that came with it; they're recorded as version 0.
+        self.protocol_version = match self.get_protocol_version() {
+            Ok(version) => Some(version),
+            Err(Error::DfuError(DfuError::OpcodeNotSupported | DfuError::InvalidOpcode)) => Some(0),
+            Err(err) => return Err(err),
+        };
+
+        self.client.set_prn(self.prn)?;
+
+        let mtu = self.client.get_mtu()?;
+        self.chunk_size = match mtu {
+            Some(mtu) => chunk_size_for_mtu(mtu)?,
+            None => 244,
+        };
+        if let Some(chunk_size) = self.chunk_size_override {
+            self.chunk_size = chunk_size;
+        }
+        self.chunk_size = self.chunk_size.min(self.client.device().max_frame()).max(1);
+
+        Ok(mtu)
+    }
+
+    fn update_module<S: ImageSource + ?Sized>(&mut self, init: &[u8], firmware: &mut S) -> Result<(), Error> {
+        self.negotiate()?;
+
+        self.transfer_object(ObjectType::Command, init)?;
+
+        match self.transfer_source(ObjectType::Data, firmware) {
+            // Creating the command object clears the device's progress, so send both
+            // again from the top.
+            Err(Error::ResumeMismatch { .. }) => {
+                let force = std::mem::replace(&mut self.force, true);
+                let result = self
+                    .transfer_object(ObjectType::Command, init)
+                    .and_then(|()| self.transfer_source(ObjectType::Data, firmware));
+                self.force = force;
+                result
             }
+            result => result,
+        }
+    }
+
+    fn update_or_abort<S: ImageSource + ?Sized>(&mut self, init: &[u8], firmware: &mut S) -> Result<(), Error> {
+        match self.update_module(init, firmware) {
+            Ok(()) => Ok(()),
+            Err(err @ Error::Cancelled { .. }) => Err(err),
             Err(err) => {
-                return Err(err);
+                self.send_abort()?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Flashes every image in `firmware`, SoftDevice and bootloader first, resuming
+    /// whatever the device already holds unless the updater was made with `force`.
+    pub fn update(&mut self, firmware: &FirmwareArchive) -> Result<(), Error> {
+        self.update_images(firmware, ModuleSelection::ALL, None)
+    }
+
+    /// Like `update`, but also reports what was sent and how long it took.
+    pub fn update_with_stats(&mut self, firmware: &FirmwareArchive) -> Result<TransferStats, Error> {
+        self.stats = TransferStats {
+            link: self.client.device().link_info(),
+            ..TransferStats::default()
+        };
+        let started = Instant::now();
+        self.update_images(firmware, ModuleSelection::ALL, None)?;
+        self.stats.elapsed = started.elapsed();
+        self.stats.protocol_version = self.protocol_version;
+        Ok(self.stats.clone())
+    }
+
+    /// Like `update`, but sends `init_override` as the (first) application's init packet
+    /// instead of the archive's `.dat`, e.g. one signed per device at flash time. Nothing
+    /// is sent if the override doesn't parse, or gives a firmware type or application
+    /// size that doesn't fit the archive's application.
+    pub fn update_with_init(&mut self, firmware: &FirmwareArchive, init_override: &[u8]) -> Result<(), Error> {
+        let init = InitPacket::parse(init_override)?;
+        let application = firmware.images().into_iter().find(|(kind, _)| *kind == ImageKind::Application);
+        if let Some((_, application)) = application {
+            // FwType APPLICATION or EXTERNAL_APPLICATION.
+            let type_fits = matches!(init.fw_type, None | Some(0) | Some(4));
+            let size_fits = init.app_size.is_none_or(|size| size as usize == application.len());
+            if !type_fits || !size_fits {
+                return Err(Error::InitPacketMismatch {
+                    image_size: application.len(),
+                });
             }
         }
+        self.update_images(firmware, ModuleSelection::ALL, Some(init_override))
+    }
+
+    /// Like `update`, but only flashes the image kinds in `modules`.
+    pub fn update_selective(&mut self, firmware: &FirmwareArchive, modules: ModuleSelection) -> Result<(), Error> {
+        self.update_images(firmware, modules, None)
+    }
+
+    fn update_images(
+        &mut self,
+        firmware: &FirmwareArchive,
+        modules: ModuleSelection,
+        init_override: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let images = firmware.selected_images(modules);
+        let last = images.len().saturating_sub(1);
+        let mut init_override = init_override;
+
+        for (index, (kind, image)) in images.into_iter().enumerate() {
+            let init = match kind {
+                ImageKind::Application => init_override.take().unwrap_or(&image.dat),
+                _ => &image.dat,
+            };
 
-        self.transfer_object(ObjectType::Command, firmware.dat.as_slice())?;
+            self.defer_execute = self.two_phase && index == last;
+            let result = self.update_or_abort(init, &mut image.bin());
+            let deferred = std::mem::take(&mut self.defer_execute);
+            result?;
 
-        self.transfer_object(ObjectType::Data, firmware.bin.as_slice())?;
+            if deferred {
+                self.pending_commit = Some(kind);
+            } else {
+                self.finish_image(kind, index == last)?;
+            }
+        }
 
         Ok(())
     }
 
-    pub fn update(&mut self, firmware: &FirmwareArchive) -> Result<(), Error> {
-        if let Some(softdevice_bootloader) = &firmware.softdevice_bootloader {
-            if let Err(err) = self.update_module(&softdevice_bootloader) {
-                self.request(AbortRequest)?;
-                return Err(err);
+    /// Flashes a single image whose firmware is read from `firmware` as it is sent,
+    /// e.g. an entry of a `StreamingArchive`, then resets as `update` would.
+    pub fn update_image<S: ImageSource + ?Sized>(
+        &mut self,
+        kind: ImageKind,
+        init: &[u8],
+        firmware: &mut S,
+    ) -> Result<(), Error> {
+        self.update_or_abort(init, firmware)?;
+        self.finish_image(kind, true)
+    }
+
+    fn finish_image(&mut self, kind: ImageKind, last: bool) -> Result<(), Error> {
+        match kind {
+            ImageKind::SoftdeviceBootloader => {
+                thread::sleep(self.bootloader_reset_delay);
+                self.reset_into_bootloader()?;
             }
-            thread::sleep(Duration::from_millis(1000));
-            self.comm.reset(ResetMode::Bootloader);
-        } else if let Some(bootloader) = &firmware.bootloader {
-            if let Err(err) = self.update_module(&bootloader) {
-                self.request(AbortRequest)?;
-                return Err(err);
+            ImageKind::Bootloader => {
+                thread::sleep(self.app_reset_delay);
+                self.reset_into_bootloader()?;
+            }
+            ImageKind::Application => {
+                thread::sleep(self.app_reset_delay);
+                // With several application images, only boot once the last one is in.
+                if self.auto_boot && last {
+                    self.client.device().reset(ResetMode::Application);
+                }
             }
-            thread::sleep(Duration::from_millis(500));
-            self.comm.reset(ResetMode::Bootloader);
         }
+        Ok(())
+    }
 
-        if let Some(application) = &firmware.application {
-            if let Err(err) = self.update_module(&application) {
-                self.request(AbortRequest)?;
-                return Err(err);
-            }
-            thread::sleep(Duration::from_millis(500));
+    fn reset_into_bootloader(&mut self) -> Result<(), Error> {
+        self.client.device().reset(ResetMode::Bootloader);
+        self.client.device().reconnect()?;
+        self.client.forget_read_timeout();
+        Ok(())
+    }
+
+    /// Executes the final data object held back by two-phase commit, activating the
+    /// image. Does nothing if no commit is pending.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if let Some(kind) = self.pending_commit.take() {
+            self.request(ObjectExecuteRequest)?;
+            self.in_transfer = false;
+            self.finish_image(kind, true)?;
         }
+        Ok(())
+    }
 
+    /// Sends `Abort`, discarding the object in progress and any commit held back by
+    /// two-phase commit. The device is left ready for a fresh `ObjectSelect`, e.g. when
+    /// the user backs out between `validate` and `update`.
+    pub fn abort(&mut self) -> Result<(), Error> {
+        self.pending_commit = None;
+        self.send_abort()
+    }
+
+    /// Negotiates with the device and describes what `update` would send, without
+    /// creating or writing any objects.
+    pub fn plan(&mut self, firmware: &FirmwareArchive) -> Result<TransferPlan, Error> {
+        let mtu = self.negotiate()?;
+        let ObjectSelectResponse {
+            max_size: command_max_size,
+            ..
+        } = self.request(ObjectSelectRequest {
+            object_type: ObjectType::Command,
+        })?;
+        let ObjectSelectResponse {
+            max_size: data_max_size,
+            ..
+        } = self.request(ObjectSelectRequest {
+            object_type: ObjectType::Data,
+        })?;
+
+        let crc = self.crc;
+        let images = firmware
+            .images()
+            .into_iter()
+            .map(|(kind, image)| ImagePlan {
+                kind,
+                init_size: image.dat.len(),
+                init_crc: crc.checksum(&image.dat),
+                command_objects: image.dat.len().div_ceil(command_max_size as usize),
+                firmware_size: image.len(),
+                firmware_crc: crc.checksum(image.bin()),
+                data_objects: image.len().div_ceil(data_max_size as usize),
+            })
+            .collect();
+
+        Ok(TransferPlan {
+            link: self.client.device().link_info(),
+            protocol_version: self.protocol_version,
+            mtu,
+            chunk_size: self.chunk_size,
+            prn: self.prn,
+            images,
+        })
+    }
+
+    /// Dry run: negotiates, then sends and executes each image's init packet so the
+    /// bootloader checks it, and aborts before any firmware data is written. Returns
+    /// the plan `update` would follow.
+    pub fn validate(&mut self, firmware: &FirmwareArchive) -> Result<TransferPlan, Error> {
+        let plan = self.plan(firmware)?;
+
+        let result = firmware
+            .images()
+            .into_iter()
+            .try_for_each(|(_, image)| self.transfer_object(ObjectType::Command, &image.dat));
+        self.send_abort()?;
+        result?;
+
+        Ok(plan)
+    }
+
+    /// Sends `init` as a command object and executes it, without touching the data
+    /// object, to check whether the bootloader accepts a signed init packet.
+    pub fn try_init(&mut self, init: &[u8]) -> Result<(), Error> {
+        self.negotiate()?;
+        self.transfer_object(ObjectType::Command, init)
+    }
+
+    /// Discards the device's current application image so the bootloader stops
+    /// trying to boot it. Sends, in order:
+    ///
+    /// - `ObjectSelect` (0x06) for the data object
+    /// - `ObjectCreate` (0x01) for a zero-length data object, invalidating the bank
+    /// - `ObjectExecute` (0x04)
+    pub fn erase_application(&mut self) -> Result<(), Error> {
+        self.request(ObjectSelectRequest {
+            object_type: ObjectType::Data,
+        })?;
+        self.request(ObjectCreateRequest {
+            object_type: ObjectType::Data,
+            object_size: 0,
+        })?;
+        self.request(ObjectExecuteRequest)?;
         Ok(())
     }
-    pub fn get_firmware_version(&mut self) -> Result<u32, Error> {
-        let GetFirmwareVersionResponse {
-            firmware_type: _,
-            version,
-            address: _,
-            length: _,
-        } = self.request(GetFirmwareVersionRequest { image: 2 })?;
-        return Ok(version);
+
+    /// The protocol version found while negotiating, 0 for a bootloader too old to
+    /// report one, or `None` before any transfer or plan.
+    pub fn protocol_version(&self) -> Option<u8> {
+        self.protocol_version
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.protocol_version == Some(0)
+    }
+
+    /// Asks the device for its DFU protocol version.
+    pub fn get_protocol_version(&mut self) -> Result<u8, Error> {
+        self.client.get_protocol_version()
+    }
+
+    /// Returns `None` from bootloaders built without the hardware version request.
+    pub fn get_hardware_version(&mut self) -> Result<Option<GetHardwareVersionResponse>, Error> {
+        if self.is_legacy() {
+            return Ok(None);
+        }
+        self.client.get_hardware_version()
+    }
+
+    /// Describes the firmware image at index `image`; see `firmware_images` for all of them.
+    pub fn get_firmware_version(&mut self, image: u8) -> Result<GetFirmwareVersionResponse, Error> {
+        self.client.get_firmware_version(image)
+    }
+
+    /// Queries image 0, 1, 2 and so on until the bootloader has no more, which it
+    /// signals with InvalidParameter. Bootloaders without the request report none.
+    pub fn firmware_images(&mut self) -> Result<Vec<GetFirmwareVersionResponse>, Error> {
+        let mut images = vec![];
+        if self.is_legacy() {
+            return Ok(images);
+        }
+        for image in 0..=u8::MAX {
+            match self.get_firmware_version(image) {
+                Ok(version) => images.push(version),
+                Err(Error::DfuError(DfuError::InvalidParameter | DfuError::OpcodeNotSupported)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(images)
+    }
+
+    /// The version of the application the bootloader reports, found by type since its
+    /// image index depends on whether a SoftDevice is present. `None` when there is no
+    /// application, or the bootloader can't say, as with those from before SDK 15.
+    pub fn application_version(&mut self) -> Result<Option<u32>, Error> {
+        let images = match self.firmware_images() {
+            Ok(images) => images,
+            // Before negotiating, a legacy bootloader isn't known to be one yet.
+            Err(Error::DfuError(DfuError::InvalidOpcode)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        Ok(images
+            .into_iter()
+            .find(|image| image.firmware_type_kind() == FirmwareType::Application)
+            .map(|image| image.version))
+    }
+
+    /// Skips the update when the device already runs the archive's application, going
+    /// by the init packet's `fw_version`. Only a match is skipped; an older archive is
+    /// still sent, leaving downgrades to the bootloader's own version check. Archives
+    /// that also carry a bootloader or SoftDevice, and devices with no application or
+    /// that can't report its version, are always flashed.
+    pub fn update_if_newer(&mut self, firmware: &FirmwareArchive) -> Result<UpdateOutcome, Error> {
+        self.update_if_newer_with(firmware, &firmware.init_packets())
+    }
+
+    /// Like `update_if_newer`, but goes by `inits`, init packets the caller has already
+    /// parsed, and perhaps corrected, instead of those parsed when the archive was
+    /// loaded. The archive's `.dat` bytes are still what gets sent.
+    pub fn update_if_newer_with(
+        &mut self,
+        firmware: &FirmwareArchive,
+        inits: &InitPacketSet,
+    ) -> Result<UpdateOutcome, Error> {
+        if let [(ImageKind::Application, _)] = firmware.images()[..] {
+            if let Some(version) = inits.get(0).and_then(|init| init.fw_version) {
+                if self.application_version()? == Some(version) {
+                    return Ok(UpdateOutcome::AlreadyCurrent { version });
+                }
+            }
+        }
+
+        self.update(firmware)?;
+        Ok(UpdateOutcome::Updated)
+    }
+}
+
+// Dropped partway through a transfer, e.g. by a panic or an early return, the updater
+// aborts so the bootloader isn't left holding a half-written object for the next session.
+impl<T: NordicDevice> Drop for Updater<'_, T> {
+    fn drop(&mut self) {
+        if self.in_transfer {
+            let _ = self.send_abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+
+    use super::*;
+    use crate::archive::{FirmwareData, ManifestInfo};
+    use crate::dfu::ExtErrorCode;
+    use crate::mock::MockDevice;
+    use crate::slip::{encode, PlainSlipCodec, SlipCodec};
+
+    struct ScriptedDevice {
+        responses: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+        stale: usize,
+        max_frame: usize,
+        resets: Vec<ResetMode>,
+        timeouts: Vec<Duration>,
+        reconnects: usize,
+        // How much had been written when each response frame started to be read.
+        written_at_read: Vec<usize>,
+    }
+
+    impl ScriptedDevice {
+        fn new(frames: &[Vec<u8>]) -> ScriptedDevice {
+            ScriptedDevice {
+                responses: Cursor::new(frames.concat()),
+                written: vec![],
+                stale: 0,
+                max_frame: usize::MAX,
+                resets: vec![],
+                timeouts: vec![],
+                reconnects: 0,
+                written_at_read: vec![],
+            }
+        }
+    }
+
+    impl Read for ScriptedDevice {
+        // Like a serial port with a read timeout, running out of script times out.
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let position = self.responses.position() as usize;
+            if position == 0 || self.responses.get_ref().get(position - 1) == Some(&0xC0) {
+                self.written_at_read.push(self.written.len());
+            }
+            match self.responses.read(buf)? {
+                0 if !buf.is_empty() => Err(std::io::ErrorKind::TimedOut.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for ScriptedDevice {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl NordicDevice for ScriptedDevice {
+        type Codec = SlipCodec;
+        fn reset(&mut self, mode: ResetMode) {
+            self.resets.push(mode);
+        }
+
+        fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+            self.timeouts.push(timeout);
+            Ok(())
+        }
+
+        fn reconnect(&mut self) -> std::io::Result<()> {
+            self.reconnects += 1;
+            Ok(())
+        }
+
+        fn drain_input(&mut self) -> std::io::Result<()> {
+            let position = self.responses.position() + self.stale as u64;
+            self.responses.set_position(position);
+            self.stale = 0;
+            Ok(())
+        }
+
+        fn max_frame(&self) -> usize {
+            self.max_frame
+        }
+    }
+
+    fn response(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x60, opcode, 0x01];
+        frame.extend_from_slice(payload);
+        frame.push(0xC0);
+        frame
+    }
+
+    fn select_response(max_size: u32, offset: u32, crc: u32) -> Vec<u8> {
+        let payload = [max_size.to_le_bytes(), offset.to_le_bytes(), crc.to_le_bytes()].concat();
+        response(0x06, &payload)
+    }
+
+    fn crc_response(offset: u32, crc: u32) -> Vec<u8> {
+        let payload = [offset.to_le_bytes(), crc.to_le_bytes()].concat();
+        response(0x03, &payload)
+    }
+
+    #[test]
+    fn errors_chain_their_source() {
+        use std::error::Error as _;
+
+        let err = Error::from(DfuError::InvalidObject);
+        assert_eq!(err.to_string(), "device returned an error: invalid object");
+        assert_eq!(err.source().unwrap().to_string(), "invalid object");
+        assert!(Error::CrcMismatch.source().is_none());
+    }
+
+    #[test]
+    fn raw_exchange_returns_frame_verbatim() {
+        let mut device = ScriptedDevice::new(&[response(0x42, &[0xDE, 0xAD])]);
+
+        let frame = Updater::new(&mut device, false)
+            .raw_exchange(0x42, &[0x01, 0xC0])
+            .unwrap();
+
+        assert_eq!(frame, vec![0x60, 0x42, 0x01, 0xDE, 0xAD]);
+        assert_eq!(device.written, vec![0x42, 0x01, 0xDB, 0xDC, 0xC0]);
+    }
+
+    #[test]
+    fn ping_retries_past_stale_bytes() {
+        let mut device = ScriptedDevice::new(&[vec![0x00], vec![0x00], response(0x09, &[0x7F])]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_ping_retries(3, Duration::from_millis(0));
+
+        assert!(updater.ping_handshake().is_ok());
+    }
+
+    #[test]
+    fn input_is_drained_before_ping() {
+        let mut device = ScriptedDevice::new(&[b"booting\r\n".to_vec(), response(0x09, &[0x7F])]);
+        device.stale = 9;
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_ping_retries(1, Duration::from_millis(0));
+
+        assert!(updater.ping_handshake().is_ok());
+    }
+
+    #[test]
+    fn ping_resyncs_after_broken_frame() {
+        let mut device = ScriptedDevice::new(&[vec![0x60, 0x09, 0xDB, 0x00, 0x01, 0xC0], response(0x09, &[0x7F])]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_ping_retries(2, Duration::from_millis(0));
+
+        assert!(updater.ping_handshake().is_ok());
+    }
+
+    #[test]
+    fn ping_gives_up_after_configured_attempts() {
+        let mut device = ScriptedDevice::new(&[vec![0x00], vec![0x00], response(0x09, &[0x7F])]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_ping_retries(2, Duration::from_millis(0));
+
+        assert!(matches!(updater.ping_handshake(), Err(Error::NoBootloaderResponse)));
+    }
+
+    #[test]
+    fn cancel_reports_committed_offset() {
+        let data = [0x5Au8; 16];
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(8, crc32::checksum_ieee(&data[..8])),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.set_cancel_flag(Arc::new(AtomicBool::new(true)));
+
+        match updater.transfer_object(ObjectType::Data, &data) {
+            Err(Error::Cancelled { offset, crc, object_type: ObjectType::Data }) => {
+                assert_eq!(offset, 8);
+                assert_eq!(crc, crc32::checksum_ieee(&data[..8]));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(updater);
+        assert!(device.written.ends_with(&[0x03, 0xC0, 0x0C, 0xC0]));
+    }
+
+    #[test]
+    fn plan_reports_negotiated_transfer() {
+        let mut device = ScriptedDevice::new(&[
+            response(0x09, &[0x7F]),
+            response(0x00, &[0x01]),
+            response(0x02, &[]),
+            response(0x07, &131u16.to_le_bytes()),
+            select_response(256, 0, 0),
+            select_response(4096, 0, 0),
+        ]);
+        let archive = FirmwareArchive {
+            bootloader: None,
+            softdevice_bootloader: None,
+            applications: vec![FirmwareData::new(vec![0; 5000], vec![1; 100])],
+            manifest: ManifestInfo::default(),
+            raw_manifest: String::new(),
+        };
+
+        let plan = Updater::new(&mut device, false).plan(&archive).unwrap();
+
+        assert_eq!(plan.link, LinkInfo::Unknown);
+        assert_eq!(plan.protocol_version, Some(1));
+        assert_eq!(plan.mtu, Some(131));
+        assert_eq!(plan.chunk_size, 64);
+        assert_eq!(plan.images.len(), 1);
+        assert_eq!(plan.images[0].command_objects, 1);
+        assert_eq!(plan.images[0].data_objects, 2);
+        assert_eq!(plan.images[0].firmware_crc, crc32::checksum_ieee(&[0; 5000]));
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(json.contains("\"kind\":\"Application\""));
+        assert!(json.contains("\"firmware_size\":5000"));
+    }
+
+    #[test]
+    fn validate_sends_init_packets_only() {
+        let init = [0x12u8; 40];
+        let mut device = ScriptedDevice::new(&[
+            response(0x09, &[0x7F]),
+            response(0x00, &[0x01]),
+            response(0x02, &[]),
+            response(0x07, &131u16.to_le_bytes()),
+            select_response(256, 0, 0),
+            select_response(4096, 0, 0),
+            select_response(256, 0, 0),
+            response(0x01, &[]),
+            crc_response(40, crc32::checksum_ieee(&init)),
+            response(0x04, &[]),
+        ]);
+        let archive = FirmwareArchive {
+            bootloader: None,
+            softdevice_bootloader: None,
+            applications: vec![FirmwareData::new(vec![0xA5; 5000], init.to_vec())],
+            manifest: ManifestInfo::default(),
+            raw_manifest: String::new(),
+        };
+
+        let plan = Updater::new(&mut device, false).validate(&archive).unwrap();
+
+        assert_eq!(plan.images[0].init_size, 40);
+        assert!(device.written.ends_with(&[0x04, 0xC0, 0x0C, 0xC0]));
+        assert!(!device.written.contains(&0xA5));
+    }
+
+    fn init_exchange(init: &[u8], execute: Vec<u8>) -> Vec<Vec<u8>> {
+        vec![
+            response(0x09, &[0x7F]),
+            vec![0x60, 0x00, 0x02, 0xC0],
+            response(0x02, &[]),
+            vec![0x60, 0x07, 0x02, 0xC0],
+            select_response(256, 0, 0),
+            response(0x01, &[]),
+            crc_response(init.len() as u32, crc32::checksum_ieee(init)),
+            execute,
+        ]
+    }
+
+    #[test]
+    fn try_init_executes_command_object_only() {
+        let init = [0x12u8; 40];
+        let mut device = ScriptedDevice::new(&init_exchange(&init, response(0x04, &[])));
+
+        Updater::new(&mut device, false).try_init(&init).unwrap();
+
+        assert!(device.written.starts_with(&[0x09, 0x7F, 0xC0]));
+        assert!(device.written.ends_with(&[0x04, 0xC0]));
+        assert!(!device.written.windows(3).any(|frame| frame == [0x06, 0x02, 0xC0]));
+    }
+
+    #[test]
+    fn try_init_reports_rejection() {
+        let init = [0x12u8; 40];
+        let mut device = ScriptedDevice::new(&init_exchange(&init, vec![0x60, 0x04, 0x0B, 0x0C, 0xC0]));
+
+        assert!(matches!(
+            Updater::new(&mut device, false).try_init(&init),
+            Err(Error::DfuError(DfuError::ExtendedError(ExtErrorCode::VerificationFailed)))
+        ));
+    }
+
+    #[test]
+    fn erase_application_sends_empty_data_object() {
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 1024, 0x1234),
+            response(0x01, &[]),
+            response(0x04, &[]),
+        ]);
+
+        Updater::new(&mut device, false).erase_application().unwrap();
+
+        assert_eq!(
+            device.written,
+            vec![0x06, 0x02, 0xC0, 0x01, 0x02, 0, 0, 0, 0, 0xC0, 0x04, 0xC0]
+        );
+    }
+
+    #[test]
+    fn chunk_size_is_capped_by_transport() {
+        let mut device = ScriptedDevice::new(&[
+            response(0x09, &[0x7F]),
+            response(0x00, &[0x01]),
+            response(0x02, &[]),
+            response(0x07, &515u16.to_le_bytes()),
+        ]);
+        device.max_frame = 20;
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.negotiate().unwrap();
+
+        assert_eq!(updater.chunk_size, 20);
+    }
+
+    #[test]
+    fn receipt_offset_catches_dropped_chunk() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, crc32::checksum_ieee(&data[..16])),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+
+        match updater.transfer_object(ObjectType::Data, &data) {
+            Err(Error::OffsetMismatch { reported, expected }) => {
+                assert_eq!(reported, 16);
+                assert_eq!(expected, 24);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn castagnoli_crc_can_be_selected() {
+        let data: Vec<u8> = (0..16).collect();
+        let crc = CrcAlgorithm::Castagnoli.checksum(&data);
+        assert_ne!(crc, crc32::checksum_ieee(&data));
+
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, crc),
+            select_response(4096, 16, crc),
+            response(0x04, &[]),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.set_crc_algorithm(CrcAlgorithm::Castagnoli);
+
+        updater.transfer_object(ObjectType::Data, &data).unwrap();
+    }
+
+    #[test]
+    fn progress_reports_chunks_and_checkpoints() {
+        let data = [0x5Au8; 16];
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, crc32::checksum_ieee(&data)),
+            select_response(4096, 16, crc32::checksum_ieee(&data)),
+            response(0x04, &[]),
+        ]);
+
+        let mut reports = vec![];
+        let mut record = |progress: Progress| reports.push((progress.bytes_written, progress.total));
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.on_progress(&mut record);
+
+        updater.transfer_object(ObjectType::Data, &data).unwrap();
+
+        drop(updater);
+        assert_eq!(reports, vec![(8, 16), (16, 16), (16, 16)]);
+    }
+
+    #[test]
+    fn crc_mismatch_resends_object() {
+        let data = [0x5Au8; 16];
+        let crc = crc32::checksum_ieee(&data);
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, !crc),
+            select_response(4096, 16, !crc),
+            response(0x01, &[]),
+            crc_response(16, crc),
+            select_response(4096, 16, crc),
+            response(0x04, &[]),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.set_max_retries(1);
+
+        updater.transfer_object(ObjectType::Data, &data).unwrap();
+        assert_eq!(updater.crc_retries(), 1);
+
+        let create = [0x01, 0x02, 0x10, 0x00, 0x00, 0x00, 0xC0];
+        drop(updater);
+        assert_eq!(device.written.windows(7).filter(|frame| *frame == create).count(), 2);
+    }
+
+    #[test]
+    fn unreceipted_tail_is_checked_by_get_crc() {
+        let data: Vec<u8> = (0..24).collect();
+        let crc = crc32::checksum_ieee(&data);
+        let head_crc = crc32::checksum_ieee(&data[..16]);
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, head_crc),
+            crc_response(24, !crc),
+            select_response(4096, 24, !crc),
+            response(0x01, &[]),
+            crc_response(16, head_crc),
+            crc_response(24, crc),
+            select_response(4096, 24, crc),
+            response(0x04, &[]),
+        ]);
+
+        // Three chunks with a receipt every two leaves the third to GetCrc.
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.prn = 2;
+        updater.set_max_retries(1);
+
+        updater.transfer_object(ObjectType::Data, &data).unwrap();
+        assert_eq!(updater.crc_retries(), 1);
+    }
+
+    #[test]
+    fn empty_object_is_created_and_executed() {
+        let mut device = ScriptedDevice::new(&[select_response(4096, 0, 0), response(0x01, &[]), response(0x04, &[])]);
+
+        Updater::new(&mut device, false).transfer_object(ObjectType::Command, &[]).unwrap();
+        assert_eq!(device.written, vec![0x06, 0x01, 0xC0, 0x01, 0x01, 0, 0, 0, 0, 0xC0, 0x04, 0xC0]);
+    }
+
+    #[test]
+    fn crc_mismatch_fails_once_retries_run_out() {
+        let data = [0x5Au8; 16];
+        let crc = crc32::checksum_ieee(&data);
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, !crc),
+            select_response(4096, 16, !crc),
+            response(0x01, &[]),
+            crc_response(16, !crc),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.set_max_retries(1);
+
+        assert!(matches!(updater.transfer_object(ObjectType::Data, &data), Err(Error::CrcMismatch)));
+    }
+
+    #[test]
+    fn pipelined_writes_run_ahead_of_receipts() {
+        let data: Vec<u8> = (0..64).collect();
+        let frames = [
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(24, crc32::checksum_ieee(&data[..24])),
+            crc_response(48, crc32::checksum_ieee(&data[..48])),
+            crc_response(64, crc32::checksum_ieee(&data)),
+            select_response(4096, 64, crc32::checksum_ieee(&data)),
+            response(0x04, &[]),
+        ];
+
+        let mut devices = vec![];
+        for pipelined in [false, true] {
+            let mut device = ScriptedDevice::new(&frames);
+            let mut updater = Updater::new(&mut device, false);
+            updater.chunk_size = 8;
+            updater.set_pipelined(pipelined);
+
+            updater.transfer_object(ObjectType::Data, &data).unwrap();
+            drop(updater);
+            devices.push(device);
+        }
+        assert_eq!(devices[0].written, devices[1].written);
+
+        // Where the chunks for bytes 24 to 47, the second receipt's burst, go out.
+        let written = &devices[0].written;
+        let position = |chunk: &[u8]| {
+            let frame = encode(&[&[0x08][..], chunk].concat());
+            written.windows(frame.len()).position(|window| window == frame).unwrap()
+        };
+        let burst_start = position(&data[24..32]);
+        let burst_end = position(&data[40..48]) + encode(&[&[0x08][..], &data[40..48]].concat()).len();
+
+        // The receipt for offset 24 is the third response read.
+        assert!(devices[0].written_at_read[2] <= burst_start);
+        assert!(devices[1].written_at_read[2] >= burst_end);
+    }
+
+    #[test]
+    fn two_phase_commit_defers_final_execute() {
+        let data = [0xA5u8; 16];
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, crc32::checksum_ieee(&data)),
+            select_response(4096, 16, crc32::checksum_ieee(&data)),
+            response(0x04, &[]),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.defer_execute = true;
+        updater.transfer_object(ObjectType::Data, &data).unwrap();
+
+        updater.pending_commit = Some(ImageKind::Bootloader);
+        updater.commit().unwrap();
+        assert!(updater.pending_commit.is_none());
+
+        drop(updater);
+        assert!(device.written.ends_with(&[0x03, 0xC0, 0x06, 0x02, 0xC0, 0x04, 0xC0]));
+        assert_eq!(device.written.iter().filter(|&&byte| byte == 0x04).count(), 1);
+    }
+
+    #[test]
+    fn verify_rejects_mismatched_image() {
+        let data = [0x5Au8; 16];
+        let crc = crc32::checksum_ieee(&data);
+        let frames = [
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, crc),
+            select_response(4096, 16, !crc),
+            response(0x04, &[]),
+        ];
+
+        let mut device = ScriptedDevice::new(&frames);
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        assert!(matches!(
+            updater.transfer_object(ObjectType::Data, &data),
+            Err(Error::VerifyMismatch { offset: 16, .. })
+        ));
+        drop(updater);
+        assert!(!device.written.windows(2).any(|frame| frame == [0x04, 0xC0]));
+
+        let mut device = ScriptedDevice::new(&[&frames[..3], &frames[4..]].concat());
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.set_verify(false);
+        updater.transfer_object(ObjectType::Data, &data).unwrap();
+    }
+
+    #[test]
+    fn auto_boot_resets_into_application() {
+        let mut device = ScriptedDevice::new(&[response(0x04, &[])]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_auto_boot(true);
+        updater.pending_commit = Some(ImageKind::Application);
+        updater.commit().unwrap();
+
+        drop(updater);
+        assert_eq!(device.resets, vec![ResetMode::Application]);
+    }
+
+    #[test]
+    fn builder_applies_settings() {
+        let mut device = ScriptedDevice::new(&[
+            response(0x09, &[0x7F]),
+            response(0x00, &[0x01]),
+            response(0x02, &[]),
+            response(0x07, &[0x00, 0x01]),
+        ]);
+
+        let mut updater = UpdaterBuilder::new()
+            .prn(10)
+            .chunk_size_override(Some(20))
+            .timeouts(Duration::from_millis(200), Duration::from_secs(10))
+            .build(&mut device);
+        updater.negotiate().unwrap();
+        assert_eq!(updater.chunk_size, 20);
+
+        drop(updater);
+        assert!(device.written.windows(4).any(|frame| frame == [0x02, 0x0A, 0x00, 0xC0]));
+        assert_eq!(device.timeouts, vec![Duration::from_millis(200)]);
+    }
+
+    #[test]
+    fn reset_delays_are_configurable() {
+        let mut device = ScriptedDevice::new(&[response(0x04, &[])]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_reset_delays(Duration::from_millis(0), Duration::from_millis(0));
+        updater.pending_commit = Some(ImageKind::SoftdeviceBootloader);
+        let started = std::time::Instant::now();
+        updater.commit().unwrap();
+
+        assert!(started.elapsed() < Duration::from_millis(500));
+        drop(updater);
+        assert_eq!(device.resets, vec![ResetMode::Bootloader]);
+        assert_eq!(device.reconnects, 1);
+    }
+
+    #[test]
+    fn legacy_bootloader_skips_version_requests() {
+        let mut device = ScriptedDevice::new(&[
+            response(0x09, &[0x7F]),
+            vec![0x60, 0x00, 0x02, 0xC0],
+            response(0x02, &[]),
+            response(0x07, &[0x00, 0x01]),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        assert_eq!(updater.protocol_version(), None);
+        updater.negotiate().unwrap();
+        assert_eq!(updater.protocol_version(), Some(0));
+        assert!(updater.get_hardware_version().unwrap().is_none());
+        assert!(updater.firmware_images().unwrap().is_empty());
+        drop(updater);
+        assert!(device.written.ends_with(&[0x07, 0xC0]));
+    }
+
+    #[test]
+    fn prn_is_sent_during_negotiation() {
+        let mut device = ScriptedDevice::new(&[
+            response(0x09, &[0x7F]),
+            response(0x00, &[0x01]),
+            response(0x02, &[]),
+            response(0x07, &[0x00, 0x01]),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_prn(10);
+        updater.negotiate().unwrap();
+
+        drop(updater);
+        assert!(device.written.windows(4).any(|frame| frame == [0x02, 0x0A, 0x00, 0xC0]));
+    }
+
+    #[test]
+    fn chunk_size_override_replaces_mtu() {
+        let frames = [
+            response(0x09, &[0x7F]),
+            response(0x00, &[0x01]),
+            response(0x02, &[]),
+            response(0x07, &[0x00, 0x01]),
+        ];
+
+        let mut device = ScriptedDevice::new(&frames);
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_chunk_size_override(Some(20));
+        updater.negotiate().unwrap();
+        assert_eq!(updater.chunk_size, 20);
+
+        let mut device = ScriptedDevice::new(&frames);
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_chunk_size_override(Some(0));
+        updater.negotiate().unwrap();
+        assert_eq!(updater.chunk_size, 127);
+    }
+
+    #[test]
+    fn tiny_mtu_is_rejected() {
+        let mut device = ScriptedDevice::new(&[
+            response(0x09, &[0x7F]),
+            response(0x00, &[0x01]),
+            response(0x02, &[]),
+            response(0x07, &2u16.to_le_bytes()),
+        ]);
+
+        assert!(matches!(Updater::new(&mut device, false).negotiate(), Err(Error::InvalidMtu(2))));
+    }
+
+    // From SDK 15 the bootloader resets itself after the final execute, so nothing
+    // more comes back; the update has still succeeded, and nothing is aborted.
+    #[test]
+    fn device_going_quiet_after_final_execute_is_success() {
+        let init = [0x12u8; 40];
+        let bin = [0xA5u8; 16];
+        let mut frames = init_exchange(&init, response(0x04, &[]));
+        frames.extend([
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, crc32::checksum_ieee(&bin)),
+            select_response(4096, 16, crc32::checksum_ieee(&bin)),
+            response(0x04, &[]),
+        ]);
+        let firmware = FirmwareArchive {
+            bootloader: None,
+            softdevice_bootloader: None,
+            applications: vec![FirmwareData::new(bin.to_vec(), init.to_vec())],
+            manifest: ManifestInfo::default(),
+            raw_manifest: String::new(),
+        };
+
+        let mut device = ScriptedDevice::new(&frames);
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_reset_delays(Duration::ZERO, Duration::ZERO);
+        updater.update(&firmware).unwrap();
+
+        drop(updater);
+        assert!(device.written.ends_with(&[0x06, 0x02, 0xC0, 0x04, 0xC0]));
+    }
+
+    #[test]
+    fn update_reports_transfer_stats() {
+        let init = [0x12u8; 40];
+        let bin = [0xA5u8; 16];
+        let mut frames = init_exchange(&init, response(0x04, &[]));
+        frames.extend([
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(16, crc32::checksum_ieee(&bin)),
+            select_response(4096, 16, crc32::checksum_ieee(&bin)),
+            response(0x04, &[]),
+        ]);
+        let firmware = FirmwareArchive {
+            bootloader: None,
+            softdevice_bootloader: None,
+            applications: vec![FirmwareData::new(bin.to_vec(), init.to_vec())],
+            manifest: ManifestInfo::default(),
+            raw_manifest: String::new(),
+        };
+
+        let mut device = ScriptedDevice::new(&frames);
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_reset_delays(Duration::from_millis(0), Duration::from_millis(0));
+        let stats = updater.update_with_stats(&firmware).unwrap();
+
+        assert_eq!(stats.command_bytes, 40);
+        assert_eq!(stats.data_bytes, 16);
+        assert_eq!(stats.checkpoints, 2);
+        assert_eq!(stats.retries, 0);
+    }
+
+    #[test]
+    fn silent_device_times_out() {
+        let mut device = ScriptedDevice::new(&[select_response(4096, 0, 0)]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.set_timeouts(Duration::from_millis(200), Duration::from_secs(10));
+
+        assert!(matches!(updater.transfer_object(ObjectType::Data, &[0x5A; 16]), Err(Error::Timeout)));
+        drop(updater);
+        assert_eq!(device.timeouts, vec![Duration::from_millis(200)]);
+    }
+
+    #[test]
+    fn dropping_mid_transfer_aborts() {
+        let mut device = ScriptedDevice::new(&[select_response(4096, 0, 0), response(0x01, &[])]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        assert!(updater.transfer_object(ObjectType::Data, &[0x5A; 16]).is_err());
+        drop(updater);
+        assert!(device.written.ends_with(&[0x03, 0xC0, 0x0C, 0xC0]));
+
+        let mut device = ScriptedDevice::new(&[response(0x09, &[0x7F])]);
+        Updater::new(&mut device, false).ping(0x7F).unwrap();
+        assert_eq!(device.written, vec![0x09, 0x7F, 0xC0]);
+    }
+
+    #[test]
+    fn abort_drops_pending_commit() {
+        let mut device = ScriptedDevice::new(&[]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.pending_commit = Some(ImageKind::Application);
+        updater.abort().unwrap();
+        updater.commit().unwrap();
+        drop(updater);
+        assert_eq!(device.written, vec![0x0C, 0xC0]);
+    }
+
+    #[test]
+    fn execute_uses_its_own_timeout() {
+        let mut device = ScriptedDevice::new(&[response(0x04, &[])]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_timeouts(Duration::from_millis(200), Duration::from_secs(10));
+        updater.request(ObjectExecuteRequest).unwrap();
+
+        drop(updater);
+        assert_eq!(device.timeouts, vec![Duration::from_secs(10)]);
+    }
+
+    // An application archive whose init packet carries `fw_version`, below 128.
+    fn versioned_archive(fw_version: u8) -> FirmwareArchive {
+        // Packet { command: Command { op_code: 1, init: InitCommand { fw_version } } }
+        let init = vec![0x0A, 0x06, 0x08, 0x01, 0x12, 0x02, 0x08, fw_version];
+        FirmwareArchive {
+            bootloader: None,
+            softdevice_bootloader: None,
+            applications: vec![FirmwareData::new(vec![0xA5; 16], init)],
+            manifest: ManifestInfo::default(),
+            raw_manifest: String::new(),
+        }
+    }
+
+    // Packet { command: Command { op_code: 1, init: InitCommand { fw_version: 2, app_size } } }
+    fn init_for_size(app_size: u8) -> Vec<u8> {
+        vec![0x0A, 0x08, 0x08, 0x01, 0x12, 0x04, 0x08, 0x02, 0x38, app_size]
+    }
+
+    #[test]
+    fn init_override_replaces_the_archive_init_packet() {
+        let mut device = MockDevice::new();
+        Updater::new(&mut device, false).update_with_init(&versioned_archive(7), &init_for_size(16)).unwrap();
+
+        assert_eq!(device.executed(ObjectType::Command), &init_for_size(16)[..]);
+        assert_eq!(device.executed(ObjectType::Data), &[0xA5; 16]);
+    }
+
+    #[test]
+    fn unfit_init_override_is_rejected_before_sending() {
+        let mut device = ScriptedDevice::new(&[]);
+        let mut updater = Updater::new(&mut device, false);
+
+        assert!(matches!(
+            updater.update_with_init(&versioned_archive(7), &[0x0A, 0x08, 0x08]),
+            Err(Error::InvalidInitPacket(_))
+        ));
+        assert!(matches!(
+            updater.update_with_init(&versioned_archive(7), &init_for_size(32)),
+            Err(Error::InitPacketMismatch { image_size: 16 })
+        ));
+        drop(updater);
+        assert!(device.written.is_empty());
+    }
+
+    #[test]
+    fn unselected_modules_are_not_sent() {
+        let firmware = FirmwareArchive {
+            bootloader: Some(FirmwareData::new(vec![0x01; 8], vec![0x02; 2])),
+            softdevice_bootloader: None,
+            applications: vec![FirmwareData::new(vec![0xA5; 16], vec![0x12; 2])],
+            manifest: ManifestInfo::default(),
+            raw_manifest: String::new(),
+        };
+
+        let mut device = MockDevice::new();
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_reset_delays(Duration::ZERO, Duration::ZERO);
+        updater.update_selective(&firmware, ModuleSelection::APPLICATION).unwrap();
+        drop(updater);
+        assert_eq!(device.executed(ObjectType::Command), &[0x12; 2]);
+        assert_eq!(device.executed(ObjectType::Data), &[0xA5; 16]);
+        assert!(device.resets().is_empty());
+
+        let mut device = MockDevice::new();
+        let mut updater = Updater::new(&mut device, false);
+        updater.set_reset_delays(Duration::ZERO, Duration::ZERO);
+        updater.update_selective(&firmware, ModuleSelection::BOOTLOADER).unwrap();
+        drop(updater);
+        assert_eq!(device.executed(ObjectType::Data), &[0x01; 8]);
+        assert_eq!(device.resets(), &[ResetMode::Bootloader]);
+    }
+
+    #[test]
+    fn current_application_is_not_reflashed() {
+        // No SoftDevice, so the application is image 1.
+        let mut device = MockDevice::new();
+        device.set_firmware_images(&[(0x02, 1), (0x01, 7)]);
+
+        let outcome = Updater::new(&mut device, false).update_if_newer(&versioned_archive(7)).unwrap();
+        assert_eq!(outcome, UpdateOutcome::AlreadyCurrent { version: 7 });
+        assert!(device.executed(ObjectType::Data).is_empty());
+
+        let outcome = Updater::new(&mut device, false).update_if_newer(&versioned_archive(6)).unwrap();
+        assert_eq!(outcome, UpdateOutcome::Updated);
+        assert_eq!(device.executed(ObjectType::Data), &[0xA5; 16]);
+    }
+
+    #[test]
+    fn update_if_newer_goes_by_given_init_packets() {
+        let firmware = versioned_archive(7);
+        let mut inits = firmware.init_packets();
+        assert_eq!(inits.get(0).unwrap().fw_version, Some(7));
+        inits.packets[0].as_mut().unwrap().fw_version = Some(8);
+
+        let mut device = MockDevice::new();
+        device.set_firmware_images(&[(0x01, 8)]);
+        let outcome = Updater::new(&mut device, false).update_if_newer_with(&firmware, &inits).unwrap();
+        assert_eq!(outcome, UpdateOutcome::AlreadyCurrent { version: 8 });
+
+        let outcome = Updater::new(&mut device, false).update_if_newer(&firmware).unwrap();
+        assert_eq!(outcome, UpdateOutcome::Updated);
+    }
+
+    #[test]
+    fn update_if_newer_flashes_when_version_is_unknown() {
+        // A blank device: SoftDevice and bootloader, but no application.
+        let mut device = MockDevice::new();
+        device.set_firmware_images(&[(0x00, 1), (0x02, 1)]);
+        let outcome = Updater::new(&mut device, false).update_if_newer(&versioned_archive(7)).unwrap();
+        assert_eq!(outcome, UpdateOutcome::Updated);
+
+        // A legacy bootloader without GetFirmwareVersion.
+        let mut device = MockDevice::new();
+        let outcome = Updater::new(&mut device, false).update_if_newer(&versioned_archive(7)).unwrap();
+        assert_eq!(outcome, UpdateOutcome::Updated);
+        assert_eq!(device.executed(ObjectType::Data), &[0xA5; 16]);
+    }
+
+    #[test]
+    fn ping_checks_echoed_id() {
+        let mut device = ScriptedDevice::new(&[response(0x09, &[0x42]), response(0x09, &[0x41])]);
+        let mut updater = Updater::new(&mut device, false);
+        updater.ping(0x42).unwrap();
+        assert!(matches!(updater.ping(0x42), Err(Error::PingMismatch)));
+        drop(updater);
+        assert_eq!(device.written, vec![0x09, 0x42, 0xC0, 0x09, 0x42, 0xC0]);
+    }
+
+    #[test]
+    fn send_raw_checks_result_and_strips_header() {
+        let mut device = ScriptedDevice::new(&[response(0x42, &[0xAA, 0xBB]), vec![0x60, 0x42, 0x0B, 0x07, 0xC0]]);
+        let mut updater = Updater::new(&mut device, false);
+
+        assert_eq!(updater.send_raw(0x42, &[0x01]).unwrap(), vec![0xAA, 0xBB]);
+        assert!(matches!(
+            updater.send_raw(0x42, &[]),
+            Err(Error::DfuError(DfuError::ExtendedError(ExtErrorCode::SdVersionFailure)))
+        ));
+    }
+
+    #[test]
+    fn reads_protocol_version() {
+        let mut device = ScriptedDevice::new(&[response(0x00, &[0x01])]);
+        assert_eq!(Updater::new(&mut device, false).get_protocol_version().unwrap(), 1);
+        assert_eq!(device.written, vec![0x00, 0xC0]);
+    }
+
+    #[test]
+    fn reads_hardware_version() {
+        let hardware = [0x52840u32, 0x41414330, 0x100000, 0x40000, 0x1000];
+        let payload: Vec<u8> = hardware.iter().flat_map(|field| field.to_le_bytes()).collect();
+        let mut device = ScriptedDevice::new(&[response(0x0A, &payload), vec![0x60, 0x0A, 0x02, 0xC0]]);
+        let mut updater = Updater::new(&mut device, false);
+
+        let hardware = updater.get_hardware_version().unwrap().unwrap();
+        assert_eq!(hardware.part_name(), Some("nRF52840"));
+        assert_eq!(hardware.rom_page_size, 0x1000);
+        assert!(updater.get_hardware_version().unwrap().is_none());
+    }
+
+    #[test]
+    fn lists_firmware_images() {
+        let image = |firmware_type: u8, version: u32, address: u32| {
+            let fields = [version, address, 0x1000];
+            let payload = [&[firmware_type][..], &fields.iter().flat_map(|f| f.to_le_bytes()).collect::<Vec<u8>>()];
+            response(0x0B, &payload.concat())
+        };
+        let mut device = ScriptedDevice::new(&[
+            image(0x00, 7002000, 0x1000),
+            image(0x01, 3, 0x26000),
+            vec![0x60, 0x0B, 0x03, 0xC0],
+        ]);
+
+        let images = Updater::new(&mut device, false).firmware_images().unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!((images[1].version, images[1].address), (3, 0x26000));
+        assert_eq!(images[0].firmware_type_kind(), FirmwareType::Softdevice);
+        assert_eq!(images[1].firmware_type_kind(), FirmwareType::Application);
+        assert_eq!(device.written, vec![0x0B, 0x00, 0xC0, 0x0B, 0x01, 0xC0, 0x0B, 0x02, 0xC0]);
+    }
+
+    #[test]
+    fn bogus_crc_offset_is_reported() {
+        let data = [0xA5u8; 16];
+        let mut device = ScriptedDevice::new(&[
+            select_response(4096, 0, 0),
+            response(0x01, &[]),
+            crc_response(64, crc32::checksum_ieee(&data)),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+
+        match updater.transfer_object(ObjectType::Data, &data) {
+            Err(Error::OffsetOutOfSync { reported, sent }) => {
+                assert_eq!(reported, 64);
+                assert_eq!(sent, 16);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn crc_offset_past_the_object_is_reported() {
+        let data = [0xA5u8; 16];
+        let mut device = ScriptedDevice::new(&[
+            select_response(8, 0, 0),
+            response(0x01, &[]),
+            crc_response(12, crc32::checksum_ieee(&data[..12])),
+        ]);
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+
+        assert!(matches!(
+            updater.transfer_object(ObjectType::Data, &data),
+            Err(Error::OffsetOutOfSync { reported: 12, sent: 8 })
+        ));
+    }
+
+    // A bridge read through PlainSlipCodec that drops the 0x60 from its responses.
+    struct BareDevice(ScriptedDevice);
+
+    impl Read for BareDevice {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for BareDevice {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl NordicDevice for BareDevice {
+        type Codec = PlainSlipCodec;
+        fn reset(&mut self, _mode: ResetMode) {}
+    }
+
+    #[test]
+    fn responses_without_sentinel_are_accepted() {
+        let data = [0xA5u8; 16];
+        let crc = crc32::checksum_ieee(&data);
+        let frames: Vec<Vec<u8>> = [
+            [&[0x06, 0x01][..], &4096u32.to_le_bytes(), &[0; 8]].concat(),
+            vec![0x01, 0x01],
+            [&[0x03, 0x01][..], &16u32.to_le_bytes(), &crc.to_le_bytes()].concat(),
+            vec![0x04, 0x01],
+        ]
+        .iter()
+        .map(|frame| encode(frame))
+        .collect();
+        let mut device = BareDevice(ScriptedDevice::new(&frames));
+
+        let mut updater = Updater::new(&mut device, false);
+        updater.chunk_size = 8;
+        updater.transfer_object(ObjectType::Command, &data).unwrap();
+        drop(updater);
+        assert!(device.0.written.ends_with(&[0x03, 0xC0, 0x04, 0xC0]));
     }
 }
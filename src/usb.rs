@@ -0,0 +1,78 @@
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+use crate::codec::DfuCodec;
+use crate::slip::DEFAULT_MAX_FRAME_LEN;
+
+// Frames each request and response with a little-endian u16 length instead of SLIP
+// escaping, for USB bootloaders that read whole packets off a raw HID or bulk
+// endpoint. The stock nRF52840 USB bootloader runs SLIP over CDC ACM and wants
+// SlipCodec instead.
+pub struct UsbDfuCodec;
+
+impl DfuCodec for UsbDfuCodec {
+    type Error = std::io::Error;
+
+    fn decoded_read<T: Read>(reader: &mut T) -> std::io::Result<Vec<u8>> {
+        let mut len = [0u8; 2];
+        reader.read_exact(&mut len)?;
+        let len = u16::from_le_bytes(len) as usize;
+        if len > DEFAULT_MAX_FRAME_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("packet length {} exceeds {} bytes", len, DEFAULT_MAX_FRAME_LEN),
+            ));
+        }
+
+        let mut frame = vec![0u8; len];
+        reader.read_exact(&mut frame)?;
+        Ok(frame)
+    }
+
+    fn encoded_write<T: Write>(writer: &mut T, buf: &[u8]) -> std::io::Result<usize> {
+        Self::encoded_write_chained(writer, &[buf])
+    }
+
+    fn encoded_write_chained<T: Write>(writer: &mut T, bufs: &[&[u8]]) -> std::io::Result<usize> {
+        Self::encoded_write_into(writer, bufs, &mut vec![])
+    }
+
+    fn encoded_write_into<T: Write>(writer: &mut T, bufs: &[&[u8]], scratch: &mut Vec<u8>) -> std::io::Result<usize> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let len = u16::try_from(len)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "packet longer than 65535 bytes"))?;
+
+        scratch.clear();
+        scratch.extend_from_slice(&len.to_le_bytes());
+        for buf in bufs {
+            scratch.extend_from_slice(buf);
+        }
+        writer.write_all(scratch)?;
+        writer.flush()?;
+        Ok(scratch.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_length_prefixed() {
+        let mut written = vec![];
+        let size = UsbDfuCodec::encoded_write_chained(&mut written, &[&[0x08], &[0xC0, 0xDB]]).unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(written, vec![0x03, 0x00, 0x08, 0xC0, 0xDB]);
+
+        let mut input: &[u8] = &[0x03, 0x00, 0x60, 0x09, 0x01, 0x02, 0x00, 0x60, 0x07];
+        assert_eq!(UsbDfuCodec::decoded_read(&mut input).unwrap(), vec![0x60, 0x09, 0x01]);
+        assert_eq!(UsbDfuCodec::decoded_read(&mut input).unwrap(), vec![0x60, 0x07]);
+    }
+
+    #[test]
+    fn oversized_packet_is_rejected() {
+        let mut input: &[u8] = &[0xFF, 0xFF];
+        let error = UsbDfuCodec::decoded_read(&mut input).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}